@@ -0,0 +1,19 @@
+const { LanguageClient } = require("vscode-languageclient/node");
+
+let client;
+
+function activate() {
+  client = new LanguageClient(
+    "vvm",
+    "vVM Language Server",
+    { command: "vvm", args: ["lsp"] },
+    { documentSelector: [{ scheme: "file", language: "vvm" }] }
+  );
+  client.start();
+}
+
+function deactivate() {
+  return client ? client.stop() : undefined;
+}
+
+module.exports = { activate, deactivate };
@@ -1,3 +1,4 @@
+npm install
 vsce package
 code --install-extension vvm-1.0.0.vsix
 rm vvm-1.0.0.vsix
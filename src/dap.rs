@@ -0,0 +1,491 @@
+use std::cell::RefCell;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use crate::json::{Json, read_message, write_message};
+use crate::{Debugger, Outcome, Program, Stop, VM, VMError, register_natives};
+
+const THREAD_ID: usize = 1;
+const STACK_REFERENCE: u64 = 1;
+const GLOBALS_REFERENCE: u64 = 2;
+
+struct Session<W: Write> {
+    output: W,
+    seq: usize,
+    debugger: Option<Debugger>,
+    stop_on_entry: bool,
+    ended: bool,
+    printed: Rc<RefCell<String>>,
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl<W: Write> Session<W> {
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> Result<(), String> {
+        fields.retain(|(_, value)| *value != Json::Null);
+        self.seq += 1;
+        fields.insert(0, ("seq", self.seq.into()));
+        write_message(&mut self.output, &Json::object(fields))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> Result<(), String> {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("success", true.into()),
+            ("command", request.get("command").clone()),
+            ("body", body),
+        ])
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> Result<(), String> {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("success", false.into()),
+            ("command", request.get("command").clone()),
+            ("message", message.into()),
+        ])
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<(), String> {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ])
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) -> Result<(), String> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> Result<(), String> {
+        self.event(
+            "output",
+            Json::object(vec![("category", category.into()), ("output", text.into())]),
+        )
+    }
+
+    fn flush_printed(&mut self) -> Result<(), String> {
+        let printed = std::mem::take(&mut *self.printed.borrow_mut());
+        if printed.is_empty() {
+            return Ok(());
+        }
+        self.output("stdout", &printed)
+    }
+
+    fn handle(&mut self, request: &Json) -> Result<bool, String> {
+        let args = request.get("arguments");
+
+        match request.get("command").as_str().unwrap_or("") {
+            "initialize" => {
+                let capabilities =
+                    Json::object(vec![("supportsConfigurationDoneRequest", true.into())]);
+                self.respond(request, capabilities)?;
+            }
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    self.respond(request, Json::Null)?;
+                    self.event("initialized", Json::Null)?;
+                }
+                Err(e) => self.fail(request, &e)?,
+            },
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args);
+                self.respond(request, body)?;
+            }
+            "configurationDone" => {
+                self.respond(request, Json::Null)?;
+
+                let at_breakpoint = match &self.debugger {
+                    Some(debugger) => debugger.breakpoints().any(|b| b == debugger.vm().ptr()),
+                    None => false,
+                };
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else if at_breakpoint {
+                    self.stopped("breakpoint", None)?;
+                } else {
+                    self.resume(Debugger::cont)?;
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", THREAD_ID.into()), ("name", "main".into())]);
+                self.respond(
+                    request,
+                    Json::object(vec![("threads", vec![thread].into())]),
+                )?;
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.respond(request, body)?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64| {
+                    Json::object(vec![
+                        ("name", name.into()),
+                        ("variablesReference", Json::Number(reference as f64)),
+                        ("expensive", false.into()),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Stack", STACK_REFERENCE),
+                    scope("Globals", GLOBALS_REFERENCE),
+                ];
+                self.respond(request, Json::object(vec![("scopes", scopes.into())]))?;
+            }
+            "variables" => {
+                let body = self.variables(args.get("variablesReference").as_u64().unwrap_or(0));
+                self.respond(request, body)?;
+            }
+            "continue" => {
+                self.respond(
+                    request,
+                    Json::object(vec![("allThreadsContinued", true.into())]),
+                )?;
+                self.resume(Debugger::cont)?;
+            }
+            "next" => {
+                self.respond(request, Json::Null)?;
+                self.resume(Debugger::step_over)?;
+            }
+            "stepIn" => {
+                self.respond(request, Json::Null)?;
+                self.resume(Debugger::step)?;
+            }
+            "stepOut" => {
+                self.respond(request, Json::Null)?;
+                self.resume(Debugger::step_out)?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Json::Null)?;
+                return Ok(false);
+            }
+            command => self.fail(request, &format!("Unsupported request '{}'", command))?,
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let path = args
+            .get("program")
+            .as_str()
+            .ok_or_else(|| "Missing 'program' in launch configuration".to_string())?;
+        let source =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let program = Program::from_source_with_debug_info(&source, path)?;
+
+        let mut vm = VM::new(program);
+        let printed = self.printed.clone();
+        register_natives(&mut vm, move |s| printed.borrow_mut().push_str(s));
+
+        self.debugger = Some(Debugger::new(vm));
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        let lines = args
+            .get("breakpoints")
+            .as_array()
+            .iter()
+            .filter_map(|b| b.get("line").as_u64())
+            .map(|line| line as usize)
+            .collect::<Vec<_>>();
+        let path = args.get("source").get("path").as_str().unwrap_or("");
+
+        if let Some(debugger) = &mut self.debugger {
+            let is_program = match &debugger.vm().program().debug_info {
+                Some(debug_info) => same_file(&debug_info.file, path),
+                None => false,
+            };
+            if is_program {
+                debugger.clear_breakpoints();
+            }
+        }
+
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            let resolved = self.add_line_breakpoint(path, line);
+            breakpoints.push(Json::object(vec![
+                ("verified", resolved.is_some().into()),
+                ("line", resolved.unwrap_or(line).into()),
+            ]));
+        }
+
+        Json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // Returns the line the breakpoint was actually placed on, if any.
+    fn add_line_breakpoint(&mut self, path: &str, line: usize) -> Option<usize> {
+        let debugger = self.debugger.as_mut()?;
+        let debug_info = debugger.vm().program().debug_info.clone()?;
+        if !same_file(&debug_info.file, path) {
+            return None;
+        }
+
+        let index = debug_info.index_for_line(line)?;
+        debugger.add_breakpoint(index).ok()?;
+        debug_info.line(index)
+    }
+
+    fn stack_trace(&self) -> Json {
+        let Some(debugger) = &self.debugger else {
+            return Json::object(vec![
+                ("stackFrames", Json::Array(vec![])),
+                ("totalFrames", 0usize.into()),
+            ]);
+        };
+        let vm = debugger.vm();
+        let last_line = vm
+            .program()
+            .debug_info
+            .as_ref()
+            .and_then(|d| d.lines.last().copied());
+
+        let frames = vm
+            .stack_trace()
+            .into_iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let mut fields = vec![
+                    ("id", id.into()),
+                    ("name", frame.function.as_deref().unwrap_or("<main>").into()),
+                    ("column", 1usize.into()),
+                ];
+                match &frame.file {
+                    Some(file) => {
+                        let line = frame.line.or(last_line).unwrap_or(1);
+                        let name = std::path::Path::new(file)
+                            .file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        fields.push(("line", line.into()));
+                        fields.push((
+                            "source",
+                            Json::object(vec![
+                                ("name", name.into()),
+                                ("path", file.as_str().into()),
+                            ]),
+                        ));
+                    }
+                    None => fields.push(("line", 0usize.into())),
+                }
+                Json::object(fields)
+            })
+            .collect::<Vec<_>>();
+
+        let total = frames.len();
+        Json::object(vec![
+            ("stackFrames", frames.into()),
+            ("totalFrames", total.into()),
+        ])
+    }
+
+    fn variables(&self, reference: u64) -> Json {
+        let variable = |name: String, value: String| {
+            Json::object(vec![
+                ("name", name.into()),
+                ("value", value.into()),
+                ("variablesReference", 0usize.into()),
+            ])
+        };
+
+        let mut variables = Vec::new();
+        if let Some(debugger) = &self.debugger {
+            let vm = debugger.vm();
+            match reference {
+                STACK_REFERENCE => {
+                    for (i, value) in vm.data_stack().iter().enumerate().rev() {
+                        variables.push(variable(format!("[{}]", i), format!("{:?}", value)));
+                    }
+                }
+                GLOBALS_REFERENCE => {
+                    let mut memory = vm.memory().iter().collect::<Vec<_>>();
+                    memory.sort_by(|a, b| a.0.cmp(b.0));
+                    for (name, value) in memory {
+                        variables.push(variable(name.clone(), format!("{:?}", value)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Json::object(vec![("variables", variables.into())])
+    }
+
+    fn resume(&mut self, action: fn(&mut Debugger) -> Result<Stop, VMError>) -> Result<(), String> {
+        let result = match (&mut self.debugger, self.ended) {
+            (Some(debugger), false) => action(debugger),
+            _ => Ok(Stop::Ended(Outcome::Finished)),
+        };
+        self.flush_printed()?;
+
+        match result {
+            Ok(Stop::Step) => self.stopped("step", None),
+            Ok(Stop::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Ok(Stop::Yielded(value)) => {
+                self.stopped("pause", Some(&format!("Yielded {:?}", value)))
+            }
+            Ok(Stop::Ended(outcome)) => {
+                let code = match outcome {
+                    Outcome::Exited(code) => code,
+                    _ => 0,
+                };
+                if !self.ended {
+                    self.ended = true;
+                    self.event(
+                        "exited",
+                        Json::object(vec![("exitCode", (code as i64).into())]),
+                    )?;
+                }
+                self.event("terminated", Json::Null)
+            }
+            Err(e) => {
+                self.ended = true;
+                self.output("stderr", &format!("Error: {}\n", e))?;
+                self.stopped("exception", Some(&e.message))
+            }
+        }
+    }
+}
+
+// Serves the Debug Adapter Protocol over the given streams until the client disconnects.
+pub fn serve_dap(mut input: impl BufRead, output: impl Write) -> Result<(), String> {
+    let mut session = Session {
+        output,
+        seq: 0,
+        debugger: None,
+        stop_on_entry: false,
+        ended: false,
+        printed: Rc::new(RefCell::new(String::new())),
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        if message.get("type").as_str() != Some("request") {
+            continue;
+        }
+        if !session.handle(&message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a session over the given requests, returning everything the adapter sent.
+    fn session(requests: Vec<Json>) -> Vec<Json> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.into_iter().enumerate() {
+            let mut fields = vec![("seq".to_string(), (seq + 1).into())];
+            fields.push(("type".to_string(), "request".into()));
+            if let Json::Object(request) = request {
+                fields.extend(request);
+            }
+            write_message(&mut input, &Json::Object(fields)).unwrap();
+        }
+        let mut output = Vec::new();
+        serve_dap(&input[..], &mut output).unwrap();
+
+        let mut output = &output[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn request(command: &str, arguments: Json) -> Json {
+        Json::object(vec![("command", command.into()), ("arguments", arguments)])
+    }
+
+    fn events(messages: &[Json]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter_map(|m| m.get("event").as_str())
+            .collect()
+    }
+
+    #[test]
+    fn unsupported_request_fails() {
+        let messages = session(vec![
+            request("initialize", Json::Null),
+            request("evaluate", Json::Null),
+            request("disconnect", Json::Null),
+        ]);
+        assert_eq!(messages[0].get("success"), &Json::Bool(true));
+        assert_eq!(messages[1].get("success"), &Json::Bool(false));
+        assert_eq!(
+            messages[1].get("message").as_str(),
+            Some("Unsupported request 'evaluate'")
+        );
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn launch_without_program_fails() {
+        let messages = session(vec![request("launch", Json::object(vec![]))]);
+        assert_eq!(
+            messages[0].get("message").as_str(),
+            Some("Missing 'program' in launch configuration")
+        );
+    }
+
+    #[test]
+    fn breakpoint_stops_program() {
+        let path = std::env::temp_dir().join(format!("vvm-dap-{}.vvm", std::process::id()));
+        std::fs::write(&path, "PUSHI 1\nPUSHI 2\nEXIT_IMM 3\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let breakpoint = Json::object(vec![("line", 2usize.into())]);
+        let messages = session(vec![
+            request("launch", Json::object(vec![("program", path.into())])),
+            request(
+                "setBreakpoints",
+                Json::object(vec![
+                    ("source", Json::object(vec![("path", path.into())])),
+                    ("breakpoints", vec![breakpoint].into()),
+                ]),
+            ),
+            request("configurationDone", Json::Null),
+            request("continue", Json::Null),
+            request("disconnect", Json::Null),
+        ]);
+        std::fs::remove_file(path).unwrap();
+
+        let verified = messages[2].get("body").get("breakpoints").as_array()[0].clone();
+        assert_eq!(verified.get("verified"), &Json::Bool(true));
+        assert_eq!(
+            events(&messages),
+            ["initialized", "stopped", "exited", "terminated"]
+        );
+        let event = |name| {
+            messages
+                .iter()
+                .find(|m| m.get("event").as_str() == Some(name))
+        };
+        let stopped = event("stopped").unwrap().get("body");
+        assert_eq!(stopped.get("reason").as_str(), Some("breakpoint"));
+        let exited = event("exited").unwrap().get("body");
+        assert_eq!(exited.get("exitCode").as_u64(), Some(3));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeSet;
+
+use crate::{Outcome, VM, VMError, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint(usize),
+    Yielded(Value),
+    Ended(Outcome),
+}
+
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_breakpoint(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.vm.program().instructions.len() {
+            return Err(format!("Instruction index {} is out of range", index));
+        }
+        self.breakpoints.insert(index);
+        Ok(())
+    }
+
+    pub fn add_label_breakpoint(&mut self, label: &str) -> Result<usize, String> {
+        let index = self.resolve_label(label)?;
+        self.add_breakpoint(index)?;
+        Ok(index)
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    pub fn remove_label_breakpoint(&mut self, label: &str) -> Result<bool, String> {
+        let index = self.resolve_label(label)?;
+        Ok(self.remove_breakpoint(index))
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    fn resolve_label(&self, label: &str) -> Result<usize, String> {
+        self.vm
+            .program()
+            .labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("Undefined label '{}'", label))
+    }
+
+    pub fn step(&mut self) -> Result<Stop, VMError> {
+        match self.vm.step()? {
+            Some(Outcome::Yielded(value)) => Ok(Stop::Yielded(value)),
+            Some(outcome) => Ok(Stop::Ended(outcome)),
+            None => Ok(Stop::Step),
+        }
+    }
+
+    // Steps one instruction, running any function it calls to completion.
+    pub fn step_over(&mut self) -> Result<Stop, VMError> {
+        let depth = self.vm.call_stack().len();
+        self.run_until(|vm| vm.call_stack().len() <= depth)
+    }
+
+    // Runs until the current function returns to its caller.
+    pub fn step_out(&mut self) -> Result<Stop, VMError> {
+        let depth = self.vm.call_stack().len();
+        if depth == 0 {
+            return self.cont();
+        }
+        self.run_until(|vm| vm.call_stack().len() < depth)
+    }
+
+    pub fn cont(&mut self) -> Result<Stop, VMError> {
+        self.run_until(|_| false)
+    }
+
+    fn run_until(&mut self, done: impl Fn(&VM) -> bool) -> Result<Stop, VMError> {
+        loop {
+            match self.vm.step()? {
+                Some(Outcome::Yielded(value)) => return Ok(Stop::Yielded(value)),
+                Some(outcome) => return Ok(Stop::Ended(outcome)),
+                None => {}
+            }
+            if done(&self.vm) {
+                return Ok(Stop::Step);
+            }
+            if self.breakpoints.contains(&self.vm.ptr()) {
+                return Ok(Stop::Breakpoint(self.vm.ptr()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+
+    fn debugger() -> Debugger {
+        let source = "
+            PUSHI 1
+            CALL f
+            PUSHI 3
+            EXIT_IMM 0
+            LABEL f
+            PUSHI 2
+            RET
+        ";
+        Debugger::new(VM::new(Program::from_source(source).unwrap()))
+    }
+
+    #[test]
+    fn cont_stops_at_breakpoint() {
+        let mut debugger = debugger();
+        let index = debugger.add_label_breakpoint("f").unwrap();
+        assert_eq!(debugger.cont().unwrap(), Stop::Breakpoint(index));
+        assert_eq!(debugger.vm().data_stack(), [Value::Int(1)]);
+        assert_eq!(debugger.cont().unwrap(), Stop::Ended(Outcome::Exited(0)));
+    }
+
+    #[test]
+    fn step_over_runs_called_function() {
+        let mut debugger = debugger();
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+        assert_eq!(debugger.vm().ptr(), 2);
+        assert_eq!(debugger.vm().data_stack(), [Value::Int(1), Value::Int(2)]);
+    }
+
+    #[test]
+    fn step_out_returns_to_caller() {
+        let mut debugger = debugger();
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(debugger.vm().call_stack().len(), 1);
+        assert_eq!(debugger.step_out().unwrap(), Stop::Step);
+        assert_eq!(debugger.vm().ptr(), 2);
+        assert!(debugger.vm().call_stack().is_empty());
+    }
+
+    #[test]
+    fn removed_breakpoint_is_not_hit() {
+        let mut debugger = debugger();
+        debugger.add_label_breakpoint("f").unwrap();
+        assert!(debugger.remove_label_breakpoint("f").unwrap());
+        assert_eq!(debugger.cont().unwrap(), Stop::Ended(Outcome::Exited(0)));
+    }
+
+    #[test]
+    fn invalid_breakpoints_are_errors() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.add_breakpoint(100).unwrap_err(),
+            "Instruction index 100 is out of range"
+        );
+        assert_eq!(
+            debugger.add_label_breakpoint("missing").unwrap_err(),
+            "Undefined label 'missing'"
+        );
+    }
+}
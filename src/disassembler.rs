@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
+use crate::optimiser::rename_labels;
 use crate::{Program, optimise};
 
 pub fn disasm(program: Program) -> String {
-    let program = optimise(&program);
+    let program = rename_labels(&optimise(&program));
 
     let mut labels = HashMap::new();
 
@@ -11,20 +12,26 @@ pub fn disasm(program: Program) -> String {
         labels.insert(*addr, name.clone());
     });
 
-    let output = program
+    program
         .instructions
         .iter()
         .enumerate()
         .map(|(idx, instr)| {
             let mut line = String::new();
-            if let Some(label) = labels.get(&idx) {
-                line.push_str(&format!("LABEL {}\n", label));
+            match (labels.get(&idx), program.functions.get(&idx)) {
+                (Some(label), Some(signature)) => line.push_str(&format!(
+                    "FUNC {} {} {}\n",
+                    label, signature.argc, signature.retc
+                )),
+                (Some(label), None) => line.push_str(&format!("LABEL {}\n", label)),
+                _ => {}
+            }
+            if let Some(location) = program.location(idx) {
+                line.push_str(&format!("    # {}\n", location));
             }
             line.push_str(&format!("    {}", instr));
             line
         })
         .collect::<Vec<String>>()
-        .join("\n");
-
-    output
-}
\ No newline at end of file
+        .join("\n")
+}
@@ -0,0 +1,128 @@
+use crate::{Function, Value};
+
+const INITIAL_THRESHOLD: usize = 256;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: u64,
+    pub allocations: u64,
+    pub freed: u64,
+    pub live: usize,
+}
+
+// Storage for values shared by reference. Slots of freed objects are reused.
+#[derive(Debug)]
+pub(crate) struct Heap {
+    objects: Vec<Option<Value>>,
+    free: Vec<usize>,
+    threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+// Collects the handles of every reference contained in `value`.
+pub(crate) fn refs(value: &Value, out: &mut Vec<usize>) {
+    match value {
+        Value::Ref(id) => out.push(*id),
+        Value::Function(Function::Closure { env, .. }) => out.push(*env),
+        Value::Array(items) => items.iter().for_each(|item| refs(item, out)),
+        Value::Map(entries) => entries.values().for_each(|item| refs(item, out)),
+        _ => {}
+    }
+}
+
+impl Heap {
+    pub(crate) fn from_slots(objects: Vec<Option<Value>>) -> Self {
+        let free = (0..objects.len())
+            .filter(|&i| objects[i].is_none())
+            .collect();
+        let live = objects.iter().filter(|o| o.is_some()).count();
+        Heap {
+            objects,
+            free,
+            threshold: INITIAL_THRESHOLD.max(live * 2),
+            stats: GcStats {
+                live,
+                ..GcStats::default()
+            },
+        }
+    }
+
+    pub(crate) fn slots(&self) -> &[Option<Value>] {
+        &self.objects
+    }
+
+    pub(crate) fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        self.stats.live >= self.threshold
+    }
+
+    pub(crate) fn alloc(&mut self, value: Value) -> usize {
+        self.stats.allocations += 1;
+        self.stats.live += 1;
+        match self.free.pop() {
+            Some(id) => {
+                self.objects[id] = Some(value);
+                id
+            }
+            None => {
+                self.objects.push(Some(value));
+                self.objects.len() - 1
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, id: usize) -> Option<&Value> {
+        self.objects.get(id).and_then(Option::as_ref)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: usize) -> Option<&mut Value> {
+        self.objects.get_mut(id).and_then(Option::as_mut)
+    }
+
+    // Marks everything reachable from `roots`, returning whether each slot was marked.
+    pub(crate) fn mark(&self, mut roots: Vec<usize>) -> Vec<bool> {
+        let mut marked = vec![false; self.objects.len()];
+        while let Some(id) = roots.pop() {
+            if id >= marked.len() || marked[id] {
+                continue;
+            }
+            marked[id] = true;
+            if let Some(value) = &self.objects[id] {
+                refs(value, &mut roots);
+            }
+        }
+        marked
+    }
+
+    // Frees every object that was not marked, returning the number of objects freed.
+    pub(crate) fn sweep(&mut self, marked: &[bool]) -> usize {
+        let mut freed = 0;
+        for (id, object) in self.objects.iter_mut().enumerate() {
+            if !marked[id] && object.is_some() {
+                *object = None;
+                self.free.push(id);
+                freed += 1;
+            }
+        }
+
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.stats.live -= freed;
+        self.threshold = INITIAL_THRESHOLD.max(self.stats.live * 2);
+        freed
+    }
+}
@@ -0,0 +1,415 @@
+use std::fmt::{Display, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            chars: source.chars().collect(),
+            index: 0,
+        };
+
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.index != parser.chars.len() {
+            return Err("Trailing characters after JSON value".to_string());
+        }
+        Ok(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(value: Vec<Json>) -> Self {
+        Json::Array(value)
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl JsonParser {
+    fn whitespace(&mut self) {
+        while self.index < self.chars.len() && self.chars[self.index].is_whitespace() {
+            self.index += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self
+            .chars
+            .get(self.index)
+            .copied()
+            .ok_or_else(|| "Unexpected end of JSON input".to_string())?;
+        self.index += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        for expected in literal.chars() {
+            if self.next()? != expected {
+                return Err(format!("Expected '{}' in JSON input", literal));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.chars.get(self.index) {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected character '{}' in JSON input", c)),
+            None => Err("Unexpected end of JSON input".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.index;
+        while self.index < self.chars.len()
+            && matches!(
+                self.chars[self.index],
+                '-' | '+' | '.' | 'e' | 'E' | '0'..='9'
+            )
+        {
+            self.index += 1;
+        }
+        let text: String = self.chars[start..self.index].iter().collect();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|e: std::num::ParseFloatError| e.to_string())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .next()?
+                .to_digit(16)
+                .ok_or_else(|| "Invalid unicode escape in JSON string".to_string())?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(out),
+                '\\' => match self.next()? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        if (0xd800..0xdc00).contains(&code) {
+                            self.expect("\\u")?;
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err("Invalid surrogate pair in JSON string".to_string());
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => return Err(format!("Invalid escape '\\{}' in JSON string", c)),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.chars.get(self.index) == Some(&']') {
+            self.index += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(Json::Array(items)),
+                c => return Err(format!("Unexpected character '{}' in JSON array", c)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.chars.get(self.index) == Some(&'}') {
+            self.index += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+            self.whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(Json::Object(fields)),
+                c => return Err(format!("Unexpected character '{}' in JSON object", c)),
+            }
+        }
+    }
+}
+
+// Reads a single `Content-Length` framed message, as used by DAP and LSP.
+pub fn read_message(input: &mut impl std::io::BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+    let mut header = String::new();
+
+    loop {
+        header.clear();
+        if input.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("Content-Length")
+        {
+            length = Some(value.trim().parse::<usize>().map_err(|e| e.to_string())?);
+        }
+    }
+
+    let length = length.ok_or_else(|| "Missing Content-Length header".to_string())?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+
+    let body = String::from_utf8(body).map_err(|e| e.to_string())?;
+    Json::parse(&body).map(Some)
+}
+
+pub fn write_message(output: &mut impl std::io::Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).map_err(|e| e.to_string())?;
+    output.flush().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surrogate_pair_is_decoded() {
+        let json = Json::parse(r#""\ud83d\ude00""#).unwrap();
+        assert_eq!(json, Json::String("\u{1f600}".to_string()));
+    }
+
+    #[test]
+    fn invalid_surrogate_pair_is_an_error() {
+        let error = Json::parse(r#""\ud83d\u0041""#).unwrap_err();
+        assert_eq!(error, "Invalid surrogate pair in JSON string");
+    }
+
+    #[test]
+    fn parse_round_trips_through_display() {
+        let source = r#"{"a":[1,-2.5,true,false,null],"b":{"c":"d\n\"e\""}}"#;
+        let json = Json::parse(source).unwrap();
+        assert_eq!(json.get("b").get("c").as_str(), Some("d\n\"e\""));
+        assert_eq!(json.get("a").as_array().len(), 5);
+        assert_eq!(json.to_string(), source);
+    }
+
+    #[test]
+    fn invalid_json_is_an_error() {
+        assert_eq!(
+            Json::parse("[1, 2").unwrap_err(),
+            "Unexpected end of JSON input"
+        );
+        assert_eq!(
+            Json::parse("{\"a\" 1}").unwrap_err(),
+            "Expected ':' in JSON input"
+        );
+        assert_eq!(
+            Json::parse("1 2").unwrap_err(),
+            "Trailing characters after JSON value"
+        );
+        assert_eq!(
+            Json::parse(r#""\q""#).unwrap_err(),
+            "Invalid escape '\\q' in JSON string"
+        );
+    }
+
+    #[test]
+    fn messages_are_framed_with_content_length() {
+        let mut out = Vec::new();
+        let message = Json::object(vec![("seq", 1usize.into())]);
+        write_message(&mut out, &message).unwrap();
+        assert_eq!(out, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+
+        let mut input = &out[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(message));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn message_without_content_length_is_an_error() {
+        let mut input = &b"Content-Type: json\r\n\r\n{}"[..];
+        assert_eq!(
+            read_message(&mut input).unwrap_err(),
+            "Missing Content-Length header"
+        );
+    }
+}
@@ -1,13 +1,29 @@
+mod dap;
+mod debugger;
 mod disassembler;
+mod heap;
+mod json;
+mod lsp;
+mod natives;
 mod opcodes;
 mod optimiser;
 mod parser;
+mod profiler;
 mod program;
+mod snapshot;
+mod tracer;
 mod vm;
 
+pub use dap::serve_dap;
+pub use debugger::*;
 pub use disassembler::disasm;
+pub use heap::GcStats;
+pub use lsp::serve_lsp;
+pub use natives::register_natives;
 pub use opcodes::*;
-pub use parser::parse;
+pub use optimiser::{optimise, tail_calls};
+pub use parser::{parse, parse_with_debug_info, parse_with_lines};
+pub use profiler::*;
 pub use program::*;
+pub use tracer::TraceWriter;
 pub use vm::*;
-pub use optimiser::optimise;
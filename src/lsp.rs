@@ -0,0 +1,602 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+
+use crate::json::{Json, read_message, write_message};
+use crate::parse;
+use crate::parser::{LABEL_LIST_MNEMONICS, LABEL_MNEMONICS};
+
+const README: &str = include_str!("../README.md");
+
+const SEVERITY_ERROR: usize = 1;
+const COMPLETION_FUNCTION: usize = 3;
+const COMPLETION_KEYWORD: usize = 14;
+const COMPLETION_REFERENCE: usize = 18;
+
+// Extracts the documentation for each instruction from the README's `### `MNEMONIC` - `0xNN`` sections.
+fn instruction_docs() -> Vec<(&'static str, &'static str)> {
+    let instructions = match README.split_once("\n## Instructions\n") {
+        Some((_, rest)) => rest.split("\n## ").next().unwrap_or(rest),
+        None => return vec![],
+    };
+
+    instructions
+        .split("\n### ")
+        .skip(1)
+        .filter_map(|section| {
+            let name = section.strip_prefix('`')?.split('`').next()?;
+            Some((name, section.trim_end()))
+        })
+        .collect()
+}
+
+struct SourceLine<'a> {
+    number: usize,
+    text: &'a str,
+    op: &'a str,
+    op_start: usize,
+    operand: &'a str,
+    operand_start: usize,
+}
+
+impl SourceLine<'_> {
+    fn op_end(&self) -> usize {
+        self.op_start + self.op.len()
+    }
+
+    fn operand_end(&self) -> usize {
+        self.operand_start + self.operand.len()
+    }
+
+    // The labels the line refers to, with the byte offset of each.
+    fn label_references(&self) -> Vec<(&str, usize)> {
+        if LABEL_MNEMONICS.contains(&self.op) {
+            vec![(self.operand, self.operand_start)]
+        } else if LABEL_LIST_MNEMONICS.contains(&self.op) {
+            self.operand
+                .split_whitespace()
+                .map(|label| {
+                    let offset = label.as_ptr() as usize - self.operand.as_ptr() as usize;
+                    (label, self.operand_start + offset)
+                })
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    fn declares_label(&self) -> bool {
+        self.op == "LABEL" || self.op == "FUNC"
+    }
+}
+
+// Splits lines the same way as the assembler, remembering byte offsets of each token.
+fn source_lines(text: &str) -> Vec<SourceLine<'_>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| {
+            let op_start = line.len() - line.trim_start().len();
+            let trimmed = line.trim();
+            let (op, rest) = match trimmed.split_once(" ") {
+                Some((o, v)) => (o.trim(), v),
+                None => (trimmed, ""),
+            };
+            let mut operand = rest.trim();
+            // The operand of a FUNC directive is its name; the counts that follow are not a label.
+            if op == "FUNC" {
+                operand = operand.split_whitespace().next().unwrap_or("");
+            }
+            let operand_start = op_start + op.len() + (rest.len() - rest.trim_start().len()) + 1;
+
+            SourceLine {
+                number,
+                text: line,
+                op,
+                op_start,
+                operand,
+                operand_start: if operand.is_empty() {
+                    op_start + op.len()
+                } else {
+                    operand_start
+                },
+            }
+        })
+        .collect()
+}
+
+fn utf16_column(line: &str, byte: usize) -> usize {
+    line[..byte.min(line.len())].encode_utf16().count()
+}
+
+fn byte_offset(line: &str, column: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= column {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn range(line: &SourceLine, start: usize, end: usize) -> Json {
+    let position = |byte| {
+        Json::object(vec![
+            ("line", line.number.into()),
+            ("character", utf16_column(line.text, byte).into()),
+        ])
+    };
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+fn operand_range(line: &SourceLine) -> Json {
+    range(line, line.operand_start, line.operand_end())
+}
+
+fn label_range(line: &SourceLine, label: &str, start: usize) -> Json {
+    range(line, start, start + label.len())
+}
+
+fn location(uri: &str, range: Json) -> Json {
+    Json::object(vec![("uri", uri.into()), ("range", range)])
+}
+
+fn diagnostic(range: Json, message: String) -> Json {
+    Json::object(vec![
+        ("range", range),
+        ("severity", SEVERITY_ERROR.into()),
+        ("source", "vvm".into()),
+        ("message", message.into()),
+    ])
+}
+
+fn diagnostics(text: &str) -> Vec<Json> {
+    let lines = source_lines(text);
+    let mut diagnostics = Vec::new();
+    let mut labels = HashMap::new();
+
+    for line in &lines {
+        if let Err(e) = parse(line.text) {
+            let end = line.operand_end().max(line.op_end());
+            diagnostics.push(diagnostic(range(line, line.op_start, end), e));
+            continue;
+        }
+
+        if line.declares_label() && labels.insert(line.operand, line.number).is_some() {
+            diagnostics.push(diagnostic(
+                operand_range(line),
+                format!("Duplicate label '{}'", line.operand),
+            ));
+        }
+    }
+
+    for line in &lines {
+        for (label, start) in line.label_references() {
+            if !labels.contains_key(label) {
+                diagnostics.push(diagnostic(
+                    label_range(line, label, start),
+                    format!("Undefined label '{}'", label),
+                ));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+enum Token<'a> {
+    Mnemonic(&'a str),
+    Label(&'a str),
+}
+
+fn token_at<'a>(lines: &'a [SourceLine<'a>], position: &Json) -> Option<Token<'a>> {
+    let number = position.get("line").as_u64()? as usize;
+    let line = lines.iter().find(|l| l.number == number)?;
+    let offset = byte_offset(line.text, position.get("character").as_u64()? as usize);
+
+    if (line.op_start..=line.op_end()).contains(&offset) {
+        return Some(Token::Mnemonic(line.op));
+    }
+    if line.declares_label() && (line.operand_start..=line.operand_end()).contains(&offset) {
+        return Some(Token::Label(line.operand));
+    }
+    line.label_references()
+        .into_iter()
+        .find(|(label, start)| (*start..=start + label.len()).contains(&offset))
+        .map(|(label, _)| Token::Label(label))
+}
+
+struct Server<W: Write> {
+    output: W,
+    documents: HashMap<String, String>,
+    docs: Vec<(&'static str, &'static str)>,
+    natives: Vec<String>,
+}
+
+impl<W: Write> Server<W> {
+    fn respond(&mut self, request: &Json, result: Json) -> Result<(), String> {
+        let message = Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", request.get("id").clone()),
+            ("result", result),
+        ]);
+        write_message(&mut self.output, &message)
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> Result<(), String> {
+        let message = Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+        write_message(&mut self.output, &message)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> Result<(), String> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => diagnostics(text),
+            None => vec![],
+        };
+        self.notify(
+            "textDocument/publishDiagnostics",
+            Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", diagnostics.into()),
+            ]),
+        )
+    }
+
+    fn handle(&mut self, message: &Json) -> Result<bool, String> {
+        let params = message.get("params");
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        match message.get("method").as_str().unwrap_or("") {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("textDocumentSync", 1usize.into()),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("completionProvider", Json::object(vec![])),
+                ]);
+                let info = Json::object(vec![
+                    ("name", "vvm".into()),
+                    ("version", env!("CARGO_PKG_VERSION").into()),
+                ]);
+                self.respond(
+                    message,
+                    Json::object(vec![("capabilities", capabilities), ("serverInfo", info)]),
+                )?;
+            }
+            "shutdown" => self.respond(message, Json::Null)?,
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .get("text")
+                    .as_str()
+                    .unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri)?;
+            }
+            "textDocument/didChange" => {
+                if let Some(change) = params.get("contentChanges").as_array().last() {
+                    let text = change.get("text").as_str().unwrap_or("");
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                self.publish_diagnostics(&uri)?;
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri)?;
+            }
+            "textDocument/hover" => {
+                let result = self.hover(&uri, params.get("position"));
+                self.respond(message, result)?;
+            }
+            "textDocument/definition" => {
+                let result = self.definition(&uri, params.get("position"));
+                self.respond(message, result)?;
+            }
+            "textDocument/references" => {
+                let include_declaration = params
+                    .get("context")
+                    .get("includeDeclaration")
+                    .as_bool()
+                    .unwrap_or(true);
+                let result = self.references(&uri, params.get("position"), include_declaration);
+                self.respond(message, result)?;
+            }
+            "textDocument/completion" => {
+                let result = self.completion(&uri, params.get("position"));
+                self.respond(message, result)?;
+            }
+            _ => {
+                // Unknown requests must still be answered; notifications are ignored.
+                if *message.get("id") != Json::Null {
+                    let error = Json::object(vec![
+                        ("code", (-32601i64).into()),
+                        ("message", "Method not found".into()),
+                    ]);
+                    let response = Json::object(vec![
+                        ("jsonrpc", "2.0".into()),
+                        ("id", message.get("id").clone()),
+                        ("error", error),
+                    ]);
+                    write_message(&mut self.output, &response)?;
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn hover(&self, uri: &str, position: &Json) -> Json {
+        let Some(text) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let lines = source_lines(text);
+
+        let contents = match token_at(&lines, position) {
+            Some(Token::Mnemonic(op)) => match self.docs.iter().find(|(name, _)| *name == op) {
+                Some((_, doc)) => format!("### {}", doc),
+                None => return Json::Null,
+            },
+            Some(Token::Label(label)) => {
+                match lines
+                    .iter()
+                    .find(|l| l.declares_label() && l.operand == label)
+                {
+                    Some(line) => format!("Label `{}`, defined on line {}", label, line.number + 1),
+                    None => return Json::Null,
+                }
+            }
+            None => return Json::Null,
+        };
+
+        Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", "markdown".into()),
+                ("value", contents.into()),
+            ]),
+        )])
+    }
+
+    fn definition(&self, uri: &str, position: &Json) -> Json {
+        let Some(text) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let lines = source_lines(text);
+
+        match token_at(&lines, position) {
+            Some(Token::Label(label)) => lines
+                .iter()
+                .find(|l| l.declares_label() && l.operand == label)
+                .map(|line| location(uri, operand_range(line)))
+                .unwrap_or(Json::Null),
+            _ => Json::Null,
+        }
+    }
+
+    fn references(&self, uri: &str, position: &Json, include_declaration: bool) -> Json {
+        let Some(text) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let lines = source_lines(text);
+
+        match token_at(&lines, position) {
+            Some(Token::Label(label)) => lines
+                .iter()
+                .flat_map(|line| {
+                    let declaration =
+                        (include_declaration && line.declares_label() && line.operand == label)
+                            .then(|| operand_range(line));
+                    let references = line
+                        .label_references()
+                        .into_iter()
+                        .filter(|(l, _)| *l == label)
+                        .map(|(l, start)| label_range(line, l, start));
+                    declaration
+                        .into_iter()
+                        .chain(references)
+                        .collect::<Vec<_>>()
+                })
+                .map(|range| location(uri, range))
+                .collect::<Vec<_>>()
+                .into(),
+            _ => Json::Null,
+        }
+    }
+
+    fn completion(&self, uri: &str, position: &Json) -> Json {
+        let Some(text) = self.documents.get(uri) else {
+            return Json::Null;
+        };
+        let number = position.get("line").as_u64().unwrap_or(0) as usize;
+        let line = text.lines().nth(number).unwrap_or("");
+        let offset = byte_offset(
+            line,
+            position.get("character").as_u64().unwrap_or(0) as usize,
+        );
+
+        let item = |label: &str, kind: usize, detail: &str| {
+            Json::object(vec![
+                ("label", label.into()),
+                ("kind", kind.into()),
+                ("detail", detail.into()),
+            ])
+        };
+
+        let before = line[..offset].trim_start();
+        let items = match before.split_once(' ') {
+            None => self
+                .docs
+                .iter()
+                .map(|(name, doc)| {
+                    let summary = doc
+                        .lines()
+                        .find(|l| !l.trim().is_empty() && !l.starts_with('`'));
+                    item(name, COMPLETION_KEYWORD, summary.unwrap_or(""))
+                })
+                .collect::<Vec<_>>(),
+            Some(("CALLNATIVE" | "PUSHNATIVE", _)) => self
+                .natives
+                .iter()
+                .map(|name| item(name, COMPLETION_FUNCTION, "native function"))
+                .collect(),
+            Some((op, _))
+                if LABEL_MNEMONICS.contains(&op) || LABEL_LIST_MNEMONICS.contains(&op) =>
+            {
+                source_lines(text)
+                    .iter()
+                    .filter(|l| l.declares_label())
+                    .map(|l| l.operand)
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .map(|label| item(label, COMPLETION_REFERENCE, "label"))
+                    .collect()
+            }
+            Some(_) => vec![],
+        };
+
+        items.into()
+    }
+}
+
+// Serves the Language Server Protocol over the given streams until the client exits.
+pub fn serve_lsp(
+    mut input: impl BufRead,
+    output: impl Write,
+    natives: Vec<String>,
+) -> Result<(), String> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+        docs: instruction_docs(),
+        natives,
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        if !server.handle(&message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "LABEL start\nPUSHI 1\nJMPTABLE start end\nLABEL end\nJMP start\n";
+
+    fn server() -> Server<Vec<u8>> {
+        let mut server = Server {
+            output: Vec::new(),
+            documents: HashMap::new(),
+            docs: instruction_docs(),
+            natives: vec!["print".to_string()],
+        };
+        server
+            .documents
+            .insert("file".to_string(), SOURCE.to_string());
+        server
+    }
+
+    fn position(line: usize, character: usize) -> Json {
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    }
+
+    fn messages(diagnostics: &[Json]) -> Vec<&str> {
+        diagnostics
+            .iter()
+            .filter_map(|d| d.get("message").as_str())
+            .collect()
+    }
+
+    #[test]
+    fn diagnostics_report_label_errors() {
+        let diagnostics = diagnostics("LABEL a\nLABEL a\nJMP b\nJMPTABLE a c\n");
+        assert_eq!(
+            messages(&diagnostics),
+            [
+                "Duplicate label 'a'",
+                "Undefined label 'b'",
+                "Undefined label 'c'"
+            ]
+        );
+        let range = diagnostics[2].get("range").get("start");
+        assert_eq!(range.get("line").as_u64(), Some(3));
+        assert_eq!(range.get("character").as_u64(), Some(11));
+    }
+
+    #[test]
+    fn diagnostics_report_parse_errors() {
+        let diagnostics = diagnostics("PUSHI 1\nBOGUS\n");
+        assert_eq!(diagnostics.len(), 1);
+        let range = diagnostics[0].get("range").get("start");
+        assert_eq!(range.get("line").as_u64(), Some(1));
+    }
+
+    #[test]
+    fn instruction_docs_are_read_from_readme() {
+        let docs = instruction_docs();
+        for name in ["EXIT", "PUSHI", "TAILCALL", "JMPTABLE", "JNNIL", "DEPTH"] {
+            assert!(docs.iter().any(|(n, _)| *n == name), "missing {}", name);
+        }
+    }
+
+    #[test]
+    fn hover_shows_instruction_docs() {
+        let hover = server().hover("file", &position(1, 2));
+        let value = hover.get("contents").get("value").as_str().unwrap();
+        assert!(value.starts_with("### `PUSHI`"));
+    }
+
+    #[test]
+    fn definition_and_references_follow_labels() {
+        let server = server();
+        let definition = server.definition("file", &position(4, 5));
+        let start = definition.get("range").get("start");
+        assert_eq!(start.get("line").as_u64(), Some(0));
+        assert_eq!(start.get("character").as_u64(), Some(6));
+
+        let references = server.references("file", &position(0, 7), true);
+        let lines = references
+            .as_array()
+            .iter()
+            .filter_map(|r| r.get("range").get("start").get("line").as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [0, 2, 4]);
+    }
+
+    #[test]
+    fn completion_offers_labels_and_natives() {
+        let mut server = server();
+        server
+            .documents
+            .insert("file".to_string(), format!("{}JMP \nCALLNATIVE \n", SOURCE));
+        let labels = |result: Json| {
+            result
+                .as_array()
+                .iter()
+                .filter_map(|item| item.get("label").as_str().map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            labels(server.completion("file", &position(5, 4))),
+            ["end", "start"]
+        );
+        assert_eq!(
+            labels(server.completion("file", &position(6, 11))),
+            ["print"]
+        );
+    }
+}
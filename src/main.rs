@@ -1,34 +1,410 @@
-use vvm::{Program, VM, parse};
+use std::io::Write;
 
-const USAGE: &str = "Usage: vvm <run|build|disasm|optimise> <infile> [outfile]";
+use vvm::{
+    Debugger, Outcome, Profiler, Program, Stop, TraceWriter, VM, parse, parse_with_debug_info,
+    register_natives,
+};
 
-fn run(file: &String) {
+const USAGE: &str = "Usage: vvm <run|debug|profile|build|disasm|optimise> <infile> [outfile]
+       vvm run <infile> [--fuel N] [--trace <tracefile> [--trace-depth N] [--trace-range start[:end]]]
+       vvm <repl|dap|lsp>";
+
+const REPL_HELP: &str = "Enter instructions to run them, or one of the following commands:
+  :stack       Show the data stack
+  :vars        Show all variables
+  :var <name>  Show a single variable
+  :reset       Clear the stack, variables and loaded code
+  :help        Show this message
+  :quit        Exit the REPL";
+
+const DEBUG_HELP: &str = "Commands:
+  step, s              Execute a single instruction
+  next, n              Execute a single instruction, stepping over calls
+  finish, f            Run until the current function returns
+  continue, c          Run until a breakpoint or the end of the program
+  break, b <target>    Set a breakpoint at an instruction index or label
+  delete, d <target>   Remove a breakpoint at an instruction index or label
+  breakpoints          List all breakpoints
+  where, w             Show the current instruction
+  stack                Show the data stack
+  calls                Show a backtrace of the call stack
+  vars                 Show all variables
+  var <name>           Show a single variable
+  help                 Show this message
+  quit, q              Exit the debugger";
+
+struct TraceOptions {
+    file: String,
+    depth: usize,
+    range: Option<(String, Option<String>)>,
+}
+
+struct RunOptions {
+    trace: Option<TraceOptions>,
+    fuel: Option<u64>,
+}
+
+// Parses `[--fuel N] [--trace <file> [--trace-depth N] [--trace-range start[:end]]]`.
+fn run_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut file = None;
+    let mut depth = 3;
+    let mut range = None;
+    let mut fuel = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--fuel" => {
+                fuel = Some(
+                    value()?
+                        .parse::<u64>()
+                        .map_err(|_| "Invalid fuel amount".to_string())?,
+                )
+            }
+            "--trace" => file = Some(value()?.clone()),
+            "--trace-depth" => {
+                depth = value()?
+                    .parse::<usize>()
+                    .map_err(|_| "Invalid trace depth".to_string())?
+            }
+            "--trace-range" => {
+                let value = value()?;
+                range = Some(match value.split_once(':') {
+                    Some((start, end)) => (start.to_string(), Some(end.to_string())),
+                    None => (value.to_string(), None),
+                })
+            }
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    let trace = match file {
+        Some(file) => Some(TraceOptions { file, depth, range }),
+        None if range.is_some() => return Err("--trace-range requires --trace".to_string()),
+        None => None,
+    };
+    Ok(RunOptions { trace, fuel })
+}
+
+fn label_index(prog: &Program, label: &str) -> usize {
+    match label.parse::<usize>() {
+        Ok(index) => index,
+        Err(_) => match prog.labels.get(label) {
+            Some(&index) => index,
+            None => {
+                eprintln!("Error: Undefined label '{}'", label);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+fn run(file: &String, options: RunOptions) {
+    let data = std::fs::read(file).expect("Failed to read input file");
+    let prog = Program::from_bytecode(data).expect("Failed to parse bytecode");
+    let mut vm = VM::new(prog);
+    register_natives(&mut vm, |s| print!("{}", s));
+
+    vm.set_fuel(options.fuel);
+    if let Some(options) = options.trace {
+        let range = options.range.map(|(start, end)| {
+            let start = label_index(vm.program(), &start);
+            let end = match end {
+                Some(end) => label_index(vm.program(), &end),
+                None => vm.program().instructions.len(),
+            };
+            start..end
+        });
+        let out = std::fs::File::create(&options.file).expect("Failed to create trace file");
+        let tracer = TraceWriter::new(std::io::BufWriter::new(out), options.depth, range);
+        vm.add_observer(tracer);
+    }
+
+    // There is no host to hand yielded values to, so just carry on.
+    let result = loop {
+        match vm.run() {
+            Ok(Outcome::Yielded(_)) => continue,
+            result => break result,
+        }
+    };
+    // Dropping the tracer flushes the trace file before the process exits.
+    drop(vm.take_observers());
+
+    match result {
+        Ok(Outcome::Exited(code)) => std::process::exit(code),
+        Ok(Outcome::Finished | Outcome::Paused | Outcome::Yielded(_)) => {}
+        Ok(Outcome::OutOfFuel) => {
+            eprintln!("Error: Out of fuel");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_vars(vm: &VM) {
+    let mut vars = vm.memory().iter().collect::<Vec<_>>();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, value) in vars {
+        println!("{} = {:?}", name, value);
+    }
+}
+
+fn print_var(vm: &VM, name: &str) {
+    match vm.get_memory(name) {
+        Some(value) => println!("{} = {:?}", name, value),
+        None => eprintln!("Error: Undefined variable '{}'", name),
+    }
+}
+
+fn read_command(line: &mut String, prompt: &str) -> bool {
+    print!("{}", prompt);
+    std::io::stdout().flush().expect("Failed to flush stdout");
+
+    line.clear();
+    let read = std::io::stdin()
+        .read_line(line)
+        .expect("Failed to read input");
+    read != 0
+}
+
+fn print_location(vm: &VM) {
+    match vm.program().instructions.get(vm.ptr()) {
+        Some(instr) => println!("{:>4}: {}", vm.ptr(), instr),
+        None => println!("{:>4}: <end of program>", vm.ptr()),
+    }
+}
+
+fn debug(file: &String) {
+    let data = std::fs::read(file).expect("Failed to read input file");
+    let prog = Program::from_bytecode(data).expect("Failed to parse bytecode");
+    let mut vm = VM::new(prog);
+    register_natives(&mut vm, |s| print!("{}", s));
+
+    let mut debugger = Debugger::new(vm);
+    let mut ended = false;
+    let mut line = String::new();
+
+    println!("vVM debugger, type help for help");
+    print_location(debugger.vm());
+
+    while read_command(&mut line, "(vdb) ") {
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+
+        let (command, arg) = match input.split_once(' ') {
+            Some((c, a)) => (c, a.trim()),
+            None => (input, ""),
+        };
+
+        let result = match command {
+            "step" | "s" | "next" | "n" | "finish" | "f" | "continue" | "c" if ended => {
+                eprintln!("The program has ended");
+                continue;
+            }
+            "step" | "s" => debugger.step(),
+            "next" | "n" => debugger.step_over(),
+            "finish" | "f" => debugger.step_out(),
+            "continue" | "c" => debugger.cont(),
+            "break" | "b" => {
+                let result = match arg.parse::<usize>() {
+                    Ok(index) => debugger.add_breakpoint(index).map(|_| index),
+                    Err(_) => debugger.add_label_breakpoint(arg),
+                };
+                match result {
+                    Ok(index) => println!("Breakpoint set at {}", index),
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+                continue;
+            }
+            "delete" | "d" => {
+                let result = match arg.parse::<usize>() {
+                    Ok(index) => Ok(debugger.remove_breakpoint(index)),
+                    Err(_) => debugger.remove_label_breakpoint(arg),
+                };
+                match result {
+                    Ok(true) => println!("Breakpoint removed"),
+                    Ok(false) => eprintln!("No breakpoint at {}", arg),
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+                continue;
+            }
+            "breakpoints" => {
+                for index in debugger.breakpoints() {
+                    match debugger.vm().program().instructions.get(index) {
+                        Some(instr) => println!("{:>4}: {}", index, instr),
+                        None => println!("{:>4}: <end of program>", index),
+                    }
+                }
+                continue;
+            }
+            "where" | "w" => {
+                print_location(debugger.vm());
+                continue;
+            }
+            "stack" => {
+                println!("{:?}", debugger.vm().data_stack());
+                continue;
+            }
+            "calls" => {
+                for frame in debugger.vm().stack_trace() {
+                    println!("    {}", frame);
+                }
+                continue;
+            }
+            "vars" => {
+                print_vars(debugger.vm());
+                continue;
+            }
+            "var" => {
+                print_var(debugger.vm(), arg);
+                continue;
+            }
+            "help" => {
+                println!("{}", DEBUG_HELP);
+                continue;
+            }
+            "quit" | "q" => break,
+            _ => {
+                eprintln!("Unknown command '{}', type help for help", command);
+                continue;
+            }
+        };
+
+        match result {
+            Ok(Stop::Step) => print_location(debugger.vm()),
+            Ok(Stop::Yielded(value) | Stop::Ended(Outcome::Yielded(value))) => {
+                println!("Program yielded {:?}", value);
+                print_location(debugger.vm());
+            }
+            Ok(Stop::Breakpoint(index)) => {
+                println!("Breakpoint hit at {}", index);
+                print_location(debugger.vm());
+            }
+            Ok(Stop::Ended(Outcome::Exited(code))) => {
+                println!("Program exited with code {}", code);
+                ended = true;
+            }
+            Ok(Stop::Ended(Outcome::Finished)) => {
+                println!("Program finished");
+                ended = true;
+            }
+            Ok(Stop::Ended(Outcome::OutOfFuel)) => {
+                println!("Program ran out of fuel");
+                ended = true;
+            }
+            Ok(Stop::Ended(Outcome::Paused)) => print_location(debugger.vm()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                print_location(debugger.vm());
+                ended = true;
+            }
+        }
+    }
+}
+
+fn profile(file: &String, outfile: Option<String>) {
     let data = std::fs::read(file).expect("Failed to read input file");
     let prog = Program::from_bytecode(data).expect("Failed to parse bytecode");
     let mut vm = VM::new(prog);
+    register_natives(&mut vm, |s| print!("{}", s));
+
+    let mut profiler = Profiler::new();
+    let result = loop {
+        match profiler.run(&mut vm) {
+            Ok(Outcome::Yielded(_)) => continue,
+            result => break result,
+        }
+    };
+
+    eprintln!("{}", profiler.profile().report(&vm));
+    if let Some(outfile) = outfile {
+        std::fs::write(outfile, profiler.profile().collapsed())
+            .expect("Failed to write output file");
+    }
+
+    match result {
+        Ok(Outcome::Exited(code)) => std::process::exit(code),
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn repl_vm() -> VM {
+    let mut vm = VM::new(Program::default());
+    register_natives(&mut vm, |s| print!("{}", s));
+    vm
+}
+
+fn repl() {
+    let mut vm = repl_vm();
+    let mut line = String::new();
+
+    println!("vVM REPL, type :help for help");
 
-    vm.register_native_handler("debug", |vm| {
-        vm.debug();
-    });
+    while read_command(&mut line, "> ") {
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = input.strip_prefix(':') {
+            let (command, arg) = match command.split_once(' ') {
+                Some((c, a)) => (c, a.trim()),
+                None => (command, ""),
+            };
 
-    vm.register_native_handler("print", |vm| {
-        let value = vm.pop();
-        match value {
-            vvm::Value::Int(i) => print!("{}", i),
-            vvm::Value::String(s) => print!("{}", s),
-            vvm::Value::Bool(b) => print!("{}", b),
-            vvm::Value::Float(f) => print!("{}", f),
-            vvm::Value::UInt(f) => print!("{}", f),
-            _ => println!("<complex value>"),
+            match command {
+                "stack" => println!("{:?}", vm.data_stack()),
+                "vars" => print_vars(&vm),
+                "var" => print_var(&vm, arg),
+                "reset" => vm = repl_vm(),
+                "help" => println!("{}", REPL_HELP),
+                "quit" => break,
+                _ => eprintln!("Unknown command ':{}', type :help for help", command),
+            }
+            continue;
         }
-    });
 
-    vm.run();
+        let prog = match Program::from_source(input) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        vm.load(prog);
+
+        loop {
+            match vm.run() {
+                Ok(Outcome::Exited(code)) => std::process::exit(code),
+                Ok(Outcome::Yielded(value)) => {
+                    println!("Yielded {:?}", value);
+                    continue;
+                }
+                Ok(_) => println!("{:?}", vm.data_stack()),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+            break;
+        }
+    }
 }
 
 fn build(file: &String, outfile: &String) {
     let source = std::fs::read_to_string(file).expect("Failed to read input file");
-    let bytecode = parse(&source).expect("Failed to parse source representation");
+    let bytecode =
+        parse_with_debug_info(&source, file).expect("Failed to parse source representation");
     std::fs::write(outfile, bytecode).expect("Failed to write output file");
 }
 
@@ -42,7 +418,7 @@ fn disasm(file: &String, outfile: &String) {
 fn optimise(file: &String, outfile: &String) {
     let data = std::fs::read(file).expect("Failed to read input file");
     let prog = Program::from_bytecode(data).expect("Failed to parse bytecode");
-    let optimised_prog = vvm::optimise(&prog);
+    let optimised_prog = vvm::tail_calls(&vvm::optimise(&prog));
     let disassembled = vvm::disasm(optimised_prog);
     let bytecode = parse(&disassembled).expect("Failed to parse source representation");
     std::fs::write(outfile, bytecode).expect("Failed to write output file");
@@ -56,6 +432,32 @@ fn main() {
             return;
         }
     };
+    if cmd == "repl" {
+        repl();
+        return;
+    }
+    if cmd == "dap" {
+        let stdin = std::io::stdin();
+        if let Err(e) = vvm::serve_dap(stdin.lock(), std::io::stdout()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if cmd == "lsp" {
+        let mut vm = VM::new(Program::default());
+        register_natives(&mut vm, |_| {});
+        let mut natives = vm.native_names().map(String::from).collect::<Vec<_>>();
+        natives.sort();
+
+        let stdin = std::io::stdin();
+        if let Err(e) = vvm::serve_lsp(stdin.lock(), std::io::stdout(), natives) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let infile = match std::env::args().nth(2) {
         Some(f) => f,
         None => {
@@ -65,7 +467,18 @@ fn main() {
     };
 
     match cmd.as_str() {
-        "run" => run(&infile),
+        "run" => {
+            let args = std::env::args().skip(3).collect::<Vec<_>>();
+            match run_options(&args) {
+                Ok(options) => run(&infile, options),
+                Err(e) => {
+                    eprintln!("Error: {}\n{}", e, USAGE);
+                    std::process::exit(1);
+                }
+            }
+        }
+        "debug" => debug(&infile),
+        "profile" => profile(&infile, std::env::args().nth(3)),
         "build" => {
             let outfile = match std::env::args().nth(3) {
                 Some(f) => f,
@@ -98,7 +511,6 @@ fn main() {
         }
         _ => {
             eprintln!("{}", USAGE);
-            return;
         }
     }
 }
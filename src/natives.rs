@@ -0,0 +1,29 @@
+use std::rc::Rc;
+
+use crate::{VM, Value};
+
+// Registers the standard `print` and `debug` natives, sending their text to `output`.
+pub fn register_natives(vm: &mut VM, output: impl Fn(&str) + 'static) {
+    let output = Rc::new(output);
+
+    let out = output.clone();
+    vm.register_native_handler("debug", move |vm| {
+        out(&vm.dump());
+    });
+
+    vm.register_native_handler("print", move |vm| {
+        let value = match vm.try_pop() {
+            Ok(value) => value,
+            Err(e) => return vm.throw(Value::String(e)),
+        };
+        match vm.resolve(&value) {
+            Value::Int(i) => output(&i.to_string()),
+            Value::String(s) => output(s),
+            Value::Bool(b) => output(&b.to_string()),
+            Value::Float(f) => output(&f.to_string()),
+            Value::UInt(f) => output(&f.to_string()),
+            Value::Nil => output("nil"),
+            _ => output("<complex value>\n"),
+        }
+    });
+}
@@ -9,6 +9,14 @@ pub const OP_PUSH_STRING: u8 = 0x14;
 pub const OP_POP: u8 = 0x15;
 pub const OP_DUP: u8 = 0x16;
 pub const OP_SWAP: u8 = 0x17;
+pub const OP_PUSH_NIL: u8 = 0x18;
+pub const OP_OVER: u8 = 0x19;
+pub const OP_ROT: u8 = 0x1a;
+pub const OP_PICK: u8 = 0x1b;
+pub const OP_ROLL: u8 = 0x1c;
+pub const OP_DROP: u8 = 0x1d;
+pub const OP_DUP2: u8 = 0x1e;
+pub const OP_DEPTH: u8 = 0x1f;
 
 pub const OP_ADD: u8 = 0x20;
 pub const OP_ADD_I: u8 = 0x21;
@@ -40,6 +48,8 @@ pub const OP_STORE: u8 = 0x42;
 pub const OP_STORE_IMM: u8 = 0x43;
 pub const OP_FREE: u8 = 0x44;
 pub const OP_FREE_IMM: u8 = 0x45;
+pub const OP_LOAD_NIL: u8 = 0x46;
+pub const OP_LOAD_NIL_IMM: u8 = 0x47;
 
 pub const OP_CMP_EQUAL: u8 = 0x50;
 pub const OP_CMP_NOT_EQUAL: u8 = 0x51;
@@ -53,5 +63,48 @@ pub const OP_JUMP_IF: u8 = 0x61;
 pub const OP_CALL: u8 = 0x62;
 pub const OP_CALL_NATIVE: u8 = 0x63;
 pub const OP_RET: u8 = 0x64;
+pub const OP_YIELD: u8 = 0x65;
+pub const OP_SPAWN: u8 = 0x66;
+pub const OP_RESUME: u8 = 0x67;
+pub const OP_DONE: u8 = 0x68;
+pub const OP_TRY: u8 = 0x69;
+pub const OP_END_TRY: u8 = 0x6a;
+pub const OP_THROW: u8 = 0x6b;
+pub const OP_PUSH_FUNCTION: u8 = 0x6c;
+pub const OP_PUSH_NATIVE: u8 = 0x6d;
+pub const OP_CALL_VALUE: u8 = 0x6e;
+pub const OP_TAIL_CALL: u8 = 0x6f;
 
 pub const OP_LABEL: u8 = 0x70;
+pub const OP_DEBUG_INFO: u8 = 0x71;
+pub const OP_FUNC: u8 = 0x72;
+
+pub const OP_MAP_NEW: u8 = 0x80;
+pub const OP_MAP_GET: u8 = 0x81;
+pub const OP_MAP_SET: u8 = 0x82;
+pub const OP_MAP_HAS: u8 = 0x83;
+pub const OP_MAP_DELETE: u8 = 0x84;
+pub const OP_MAP_KEYS: u8 = 0x85;
+pub const OP_MAP_LEN: u8 = 0x86;
+
+pub const OP_BOX: u8 = 0x90;
+pub const OP_UNBOX: u8 = 0x91;
+pub const OP_SET_REF: u8 = 0x92;
+
+pub const OP_MAKE_CLOSURE: u8 = 0xa0;
+pub const OP_GET_UPVALUE: u8 = 0xa1;
+pub const OP_SET_UPVALUE: u8 = 0xa2;
+
+pub const OP_JUMP_TABLE: u8 = 0xb0;
+pub const OP_JUMP_VALUE: u8 = 0xb1;
+pub const OP_JUMP_IF_NOT: u8 = 0xb2;
+pub const OP_JUMP_EQUAL: u8 = 0xb3;
+pub const OP_JUMP_NOT_EQUAL: u8 = 0xb4;
+pub const OP_JUMP_GREATER_THAN: u8 = 0xb5;
+pub const OP_JUMP_LESS_THAN: u8 = 0xb6;
+pub const OP_JUMP_GREATER_EQUAL: u8 = 0xb7;
+pub const OP_JUMP_LESS_EQUAL: u8 = 0xb8;
+pub const OP_JUMP_ZERO: u8 = 0xb9;
+pub const OP_JUMP_NOT_ZERO: u8 = 0xba;
+pub const OP_JUMP_NIL: u8 = 0xbb;
+pub const OP_JUMP_NOT_NIL: u8 = 0xbc;
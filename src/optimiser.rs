@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{Instruction, Program};
+use crate::{DebugInfo, Instruction, Program};
 
 pub fn optimise(program: &Program) -> Program {
     let mut instructions = vec![];
+    let targets = program.labels.values().copied().collect::<HashSet<_>>();
 
     let mut prev_was_store_imm = false;
     let mut prev_store_imm_name = String::new();
@@ -16,7 +17,12 @@ pub fn optimise(program: &Program) -> Program {
                 prev_was_store_imm = true;
                 prev_store_imm_name = name.clone();
             }
-            Instruction::LoadImm(name) if prev_was_store_imm && *name == prev_store_imm_name => {
+            // A label on the load means it can be reached without the store running first.
+            Instruction::LoadImm(name)
+                if prev_was_store_imm
+                    && *name == prev_store_imm_name
+                    && !targets.contains(&(instructions.len() - 1)) =>
+            {
                 instructions.pop(); // Remove the LoadImm
                 instructions.pop(); // Remove the StoreImm
                 prev_was_store_imm = false;
@@ -30,6 +36,116 @@ pub fn optimise(program: &Program) -> Program {
         }
     }
 
+    let program = Program {
+        instructions,
+        labels: program.labels.clone(),
+        functions: program.functions.clone(),
+        debug_info: program.debug_info.clone(),
+    };
+    fuse_branches(&program)
+}
+
+// Fuses `CMPxx; JMPIF x` into the matching compare-and-branch instruction, unless a label
+// targets the JMPIF.
+fn fuse_branches(program: &Program) -> Program {
+    let targets = program.labels.values().copied().collect::<HashSet<_>>();
+    let mut instructions = vec![];
+    let mut removed = HashSet::new();
+
+    for (index, instr) in program.instructions.iter().enumerate() {
+        if removed.contains(&index) {
+            continue;
+        }
+        let fused = match (instr, program.instructions.get(index + 1)) {
+            (_, Some(Instruction::JumpIf(_))) if targets.contains(&(index + 1)) => None,
+            (Instruction::CmpEqual, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpEqual(l.clone()))
+            }
+            (Instruction::CmpNotEqual, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpNotEqual(l.clone()))
+            }
+            (Instruction::CmpGreaterThan, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpGreaterThan(l.clone()))
+            }
+            (Instruction::CmpLessThan, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpLessThan(l.clone()))
+            }
+            (Instruction::CmpGreaterEqual, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpGreaterEqual(l.clone()))
+            }
+            (Instruction::CmpLessEqual, Some(Instruction::JumpIf(l))) => {
+                Some(Instruction::JumpLessEqual(l.clone()))
+            }
+            _ => None,
+        };
+        match fused {
+            Some(fused) => {
+                instructions.push(fused);
+                removed.insert(index + 1);
+            }
+            None => instructions.push(instr.clone()),
+        }
+    }
+
+    without(program, instructions, &removed)
+}
+
+// Rewrites `CALL x; RET` into `TAILCALL x; RET`, which reuses the current frame. The RET is
+// kept for when the VM has to make a regular call instead. This is only applied by
+// `vvm optimise`, as the tail-called function no longer shows up in profiles and stack traces.
+pub fn tail_calls(program: &Program) -> Program {
+    let mut optimised = program.clone();
+    for index in 1..program.instructions.len() {
+        if let (Instruction::Call(label), Instruction::Ret) = (
+            &program.instructions[index - 1],
+            &program.instructions[index],
+        ) {
+            optimised.instructions[index - 1] = Instruction::TailCall(label.clone());
+        }
+    }
+    optimised
+}
+
+// Rebuilds `program` around `instructions`, which replace its own with those at the `removed`
+// indices left out. Labels, function signatures and debug lines are moved to match.
+fn without(program: &Program, instructions: Vec<Instruction>, removed: &HashSet<usize>) -> Program {
+    // The new index of each old instruction, or of the next one kept if it was removed.
+    let mut new_index = Vec::with_capacity(program.instructions.len() + 1);
+    let mut next = 0;
+    for index in 0..=program.instructions.len() {
+        new_index.push(next);
+        if !removed.contains(&index) {
+            next += 1;
+        }
+    }
+    let remap = |addr: usize| new_index.get(addr).copied().unwrap_or(addr);
+
+    Program {
+        instructions,
+        labels: program
+            .labels
+            .iter()
+            .map(|(name, &addr)| (name.clone(), remap(addr)))
+            .collect(),
+        functions: program
+            .functions
+            .iter()
+            .map(|(&addr, &signature)| (remap(addr), signature))
+            .collect(),
+        debug_info: program.debug_info.as_ref().map(|debug_info| DebugInfo {
+            file: debug_info.file.clone(),
+            lines: debug_info
+                .lines
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !removed.contains(index))
+                .map(|(_, &line)| line)
+                .collect(),
+        }),
+    }
+}
+
+pub(crate) fn rename_labels(program: &Program) -> Program {
     let mut names = HashMap::<String, usize>::new();
     let mut labels = HashMap::<String, usize>::new();
 
@@ -41,7 +157,7 @@ pub fn optimise(program: &Program) -> Program {
 
     let mut final_instructions = vec![];
 
-    for instr in &instructions {
+    for instr in &program.instructions {
         match instr {
             Instruction::Jump(l) => {
                 let target = names.get(l).expect("Label not found");
@@ -55,6 +171,73 @@ pub fn optimise(program: &Program) -> Program {
                 let target = names.get(l).expect("Label not found");
                 final_instructions.push(Instruction::Call(format!("{}", target)));
             }
+            Instruction::Spawn(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::Spawn(format!("{}", target)));
+            }
+            Instruction::Try(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::Try(format!("{}", target)));
+            }
+            Instruction::PushFunction(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::PushFunction(format!("{}", target)));
+            }
+            Instruction::JumpTable(default, l) => {
+                let target = |l: &String| names.get(l).expect("Label not found").to_string();
+                final_instructions.push(Instruction::JumpTable(
+                    target(default),
+                    l.iter().map(target).collect(),
+                ));
+            }
+            Instruction::JumpIfNot(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpIfNot(format!("{}", target)));
+            }
+            Instruction::JumpEqual(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpEqual(format!("{}", target)));
+            }
+            Instruction::JumpNotEqual(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpNotEqual(format!("{}", target)));
+            }
+            Instruction::JumpGreaterThan(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpGreaterThan(format!("{}", target)));
+            }
+            Instruction::JumpLessThan(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpLessThan(format!("{}", target)));
+            }
+            Instruction::JumpGreaterEqual(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpGreaterEqual(format!("{}", target)));
+            }
+            Instruction::JumpLessEqual(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpLessEqual(format!("{}", target)));
+            }
+            Instruction::JumpZero(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpZero(format!("{}", target)));
+            }
+            Instruction::JumpNotZero(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpNotZero(format!("{}", target)));
+            }
+            Instruction::JumpNil(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpNil(format!("{}", target)));
+            }
+            Instruction::JumpNotNil(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::JumpNotNil(format!("{}", target)));
+            }
+            Instruction::TailCall(l) => {
+                let target = names.get(l).expect("Label not found");
+                final_instructions.push(Instruction::TailCall(format!("{}", target)));
+            }
             _ => {
                 final_instructions.push(instr.clone());
             }
@@ -64,5 +247,57 @@ pub fn optimise(program: &Program) -> Program {
     Program {
         instructions: final_instructions,
         labels,
+        functions: program.functions.clone(),
+        debug_info: program.debug_info.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimised(source: &str) -> Vec<String> {
+        let program = optimise(&Program::from_source(source).unwrap());
+        program.instructions.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn store_and_load_are_fused() {
+        assert_eq!(
+            optimised("PUSHI 1\nSTORE_IMM x\nLOAD_IMM x"),
+            ["PUSHI 1", "DUP", "STORE_IMM x"]
+        );
+    }
+
+    #[test]
+    fn store_and_load_are_not_fused_when_a_label_targets_the_load() {
+        assert_eq!(
+            optimised("PUSHI 1\nSTORE_IMM x\nLABEL l\nLOAD_IMM x\nJMP l"),
+            ["PUSHI 1", "STORE_IMM x", "LOAD_IMM x", "JMP l"]
+        );
+    }
+
+    #[test]
+    fn tail_calls_are_only_rewritten_on_request() {
+        let source = "CALL f\nEXIT_IMM 0\nLABEL f\nCALL g\nRET\nLABEL g\nRET";
+        assert!(!optimised(source).contains(&"TAILCALL g".to_string()));
+        let program = tail_calls(&Program::from_source(source).unwrap());
+        let instructions = program.instructions.iter().map(|i| i.to_string());
+        assert_eq!(
+            instructions.collect::<Vec<_>>(),
+            ["CALL f", "EXIT_IMM 0", "TAILCALL g", "RET", "RET"]
+        );
+    }
+
+    #[test]
+    fn comparisons_are_fused_into_branches() {
+        assert_eq!(
+            optimised("LABEL l\nPUSHI 1\nPUSHI 2\nCMPLT\nJMPIF l"),
+            ["PUSHI 1", "PUSHI 2", "JLT l"]
+        );
+        assert_eq!(
+            optimised("PUSHI 1\nPUSHI 2\nCMPLT\nLABEL l\nJMPIF l"),
+            ["PUSHI 1", "PUSHI 2", "CMPLT", "JMPIF l"]
+        );
     }
 }
@@ -1,10 +1,39 @@
 use crate::opcodes::*;
 
+// Mnemonics whose operand names a label.
+pub(crate) const LABEL_MNEMONICS: &[&str] = &[
+    "JMP", "JMPIF", "CALL", "SPAWN", "TRY", "PUSHFN", "TAILCALL", "JMPIFNOT", "JEQ", "JNE", "JGT",
+    "JLT", "JGE", "JLE", "JZ", "JNZ", "JNIL", "JNNIL",
+];
+// Mnemonics whose operands are a list of labels.
+pub(crate) const LABEL_LIST_MNEMONICS: &[&str] = &["JMPTABLE"];
+
 pub fn parse(source: &str) -> Result<Vec<u8>, String> {
-    let lines = source.lines();
-    let mut bytecode = Vec::new();
+    parse_with_lines(source).map(|(bytecode, _)| bytecode)
+}
 
+// Assembles the source and appends a debug info section mapping each instruction to its line in `file`.
+pub fn parse_with_debug_info(source: &str, file: &str) -> Result<Vec<u8>, String> {
+    let (mut bytecode, lines) = parse_with_lines(source)?;
+
+    bytecode.push(OP_DEBUG_INFO);
+    let file_bytes = file.as_bytes();
+    bytecode.extend_from_slice(&(file_bytes.len() as u32).to_le_bytes());
+    bytecode.extend_from_slice(file_bytes);
+    bytecode.extend_from_slice(&(lines.len() as u32).to_le_bytes());
     for line in lines {
+        bytecode.extend_from_slice(&(line as u32).to_le_bytes());
+    }
+
+    Ok(bytecode)
+}
+
+// Also returns the 1-based source line of each assembled instruction.
+pub fn parse_with_lines(source: &str) -> Result<(Vec<u8>, Vec<usize>), String> {
+    let mut bytecode = Vec::new();
+    let mut lines = Vec::new();
+
+    for (line_no, line) in source.lines().enumerate() {
         if line.trim().is_empty() || line.trim_start().starts_with('#') {
             continue;
         }
@@ -65,6 +94,32 @@ pub fn parse(source: &str) -> Result<Vec<u8>, String> {
             "POP" => bytecode.push(OP_POP),
             "DUP" => bytecode.push(OP_DUP),
             "SWAP" => bytecode.push(OP_SWAP),
+            "PUSHN" => bytecode.push(OP_PUSH_NIL),
+            "OVER" => bytecode.push(OP_OVER),
+            "ROT" => bytecode.push(OP_ROT),
+            "PICK" => {
+                bytecode.push(OP_PICK);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "ROLL" => {
+                bytecode.push(OP_ROLL);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "DROP" => {
+                bytecode.push(OP_DROP);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "DUP2" => bytecode.push(OP_DUP2),
+            "DEPTH" => bytecode.push(OP_DEPTH),
 
             "ADD" => bytecode.push(OP_ADD),
             "ADDI" => {
@@ -169,6 +224,7 @@ pub fn parse(source: &str) -> Result<Vec<u8>, String> {
                     .map_err(|e: std::num::ParseIntError| e.to_string())?;
                 bytecode.extend_from_slice(&imm.to_le_bytes());
             }
+            "EXP" => bytecode.push(OP_EXP),
             "EXPI" => {
                 bytecode.push(OP_EXP_I);
                 let imm: i64 = val
@@ -215,6 +271,14 @@ pub fn parse(source: &str) -> Result<Vec<u8>, String> {
                 bytecode.push(len);
                 bytecode.extend_from_slice(imm_bytes);
             }
+            "LOADN" => bytecode.push(OP_LOAD_NIL),
+            "LOADN_IMM" => {
+                bytecode.push(OP_LOAD_NIL_IMM);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
 
             "CMPEQ" => bytecode.push(OP_CMP_EQUAL),
             "CMPNE" => bytecode.push(OP_CMP_NOT_EQUAL),
@@ -252,6 +316,175 @@ pub fn parse(source: &str) -> Result<Vec<u8>, String> {
                 bytecode.extend_from_slice(imm_bytes);
             }
             "RET" => bytecode.push(OP_RET),
+            "YIELD" => bytecode.push(OP_YIELD),
+            "SPAWN" => {
+                bytecode.push(OP_SPAWN);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "RESUME" => bytecode.push(OP_RESUME),
+            "DONE" => bytecode.push(OP_DONE),
+            "TRY" => {
+                bytecode.push(OP_TRY);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "ENDTRY" => bytecode.push(OP_END_TRY),
+            "THROW" => bytecode.push(OP_THROW),
+            "PUSHFN" => {
+                bytecode.push(OP_PUSH_FUNCTION);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "PUSHNATIVE" => {
+                bytecode.push(OP_PUSH_NATIVE);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "CALLV" => bytecode.push(OP_CALL_VALUE),
+            "TAILCALL" => {
+                bytecode.push(OP_TAIL_CALL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+
+            "MAPNEW" => bytecode.push(OP_MAP_NEW),
+            "MAPGET" => bytecode.push(OP_MAP_GET),
+            "MAPSET" => bytecode.push(OP_MAP_SET),
+            "MAPHAS" => bytecode.push(OP_MAP_HAS),
+            "MAPDEL" => bytecode.push(OP_MAP_DELETE),
+            "MAPKEYS" => bytecode.push(OP_MAP_KEYS),
+            "MAPLEN" => bytecode.push(OP_MAP_LEN),
+
+            "BOX" => bytecode.push(OP_BOX),
+            "UNBOX" => bytecode.push(OP_UNBOX),
+            "SETREF" => bytecode.push(OP_SET_REF),
+
+            "MAKECLOSURE" => {
+                bytecode.push(OP_MAKE_CLOSURE);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "GETUPVAL" => {
+                bytecode.push(OP_GET_UPVALUE);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "SETUPVAL" => {
+                bytecode.push(OP_SET_UPVALUE);
+                let imm: u8 = val
+                    .parse()
+                    .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                bytecode.push(imm);
+            }
+            "JMPTABLE" => {
+                // The default label comes first, followed by the table.
+                let labels = val.split_whitespace().collect::<Vec<_>>();
+                if labels.is_empty() {
+                    return Err("JMPTABLE expects a default label".to_string());
+                }
+                bytecode.push(OP_JUMP_TABLE);
+                bytecode.extend_from_slice(&(labels.len() as u32 - 1).to_le_bytes());
+                for label in labels {
+                    let imm_bytes = label.as_bytes();
+                    let len = imm_bytes.len() as u8;
+                    bytecode.push(len);
+                    bytecode.extend_from_slice(imm_bytes);
+                }
+            }
+            "JMPV" => bytecode.push(OP_JUMP_VALUE),
+            "JMPIFNOT" => {
+                bytecode.push(OP_JUMP_IF_NOT);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JEQ" => {
+                bytecode.push(OP_JUMP_EQUAL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JNE" => {
+                bytecode.push(OP_JUMP_NOT_EQUAL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JGT" => {
+                bytecode.push(OP_JUMP_GREATER_THAN);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JLT" => {
+                bytecode.push(OP_JUMP_LESS_THAN);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JGE" => {
+                bytecode.push(OP_JUMP_GREATER_EQUAL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JLE" => {
+                bytecode.push(OP_JUMP_LESS_EQUAL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JZ" => {
+                bytecode.push(OP_JUMP_ZERO);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JNZ" => {
+                bytecode.push(OP_JUMP_NOT_ZERO);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JNIL" => {
+                bytecode.push(OP_JUMP_NIL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+            "JNNIL" => {
+                bytecode.push(OP_JUMP_NOT_NIL);
+                let imm_bytes = val.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+            }
+
             "LABEL" => {
                 bytecode.push(OP_LABEL);
                 let imm_bytes = val.as_bytes();
@@ -259,12 +492,35 @@ pub fn parse(source: &str) -> Result<Vec<u8>, String> {
                 bytecode.push(len);
                 bytecode.extend_from_slice(imm_bytes);
             }
+            "FUNC" => {
+                let parts = val.split_whitespace().collect::<Vec<_>>();
+                let [name, argc, retc] = parts[..] else {
+                    return Err(
+                        "FUNC expects a name, an argument count and a result count".to_string()
+                    );
+                };
+                bytecode.push(OP_FUNC);
+                let imm_bytes = name.as_bytes();
+                let len = imm_bytes.len() as u8;
+                bytecode.push(len);
+                bytecode.extend_from_slice(imm_bytes);
+                for count in [argc, retc] {
+                    let imm: u8 = count
+                        .parse()
+                        .map_err(|e: std::num::ParseIntError| e.to_string())?;
+                    bytecode.push(imm);
+                }
+            }
 
             _ => {
                 return Err(format!("Unknown instruction: {}", op));
             }
         }
+
+        if op != "LABEL" && op != "FUNC" {
+            lines.push(line_no + 1);
+        }
     }
 
-    Ok(bytecode)
+    Ok((bytecode, lines))
 }
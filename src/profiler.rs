@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Outcome, VM, VMError};
+
+const MAIN: &str = "<main>";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub total: u64,
+    pub by_opcode: HashMap<String, u64>,
+    pub by_instruction: HashMap<usize, u64>,
+    pub functions: HashMap<String, FunctionProfile>,
+    pub stacks: HashMap<Vec<String>, u64>,
+}
+
+pub struct Profiler {
+    profile: Profile,
+    stack: Vec<String>,
+    depth: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            profile: Profile::default(),
+            stack: vec![MAIN.to_string()],
+            depth: 0,
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    // Records the instruction the VM is about to execute.
+    pub fn before_step(&mut self, vm: &VM) {
+        let Some(instr) = vm.program().instructions.get(vm.ptr()) else {
+            return;
+        };
+        let profile = &mut self.profile;
+
+        profile.total += 1;
+        *profile.by_instruction.entry(vm.ptr()).or_default() += 1;
+        let instr = instr.to_string();
+        let opcode = instr.split(' ').next().unwrap_or_default();
+        *profile.by_opcode.entry(opcode.to_string()).or_default() += 1;
+
+        let mut seen = HashSet::new();
+        for function in &self.stack {
+            if seen.insert(function) {
+                profile
+                    .functions
+                    .entry(function.clone())
+                    .or_default()
+                    .inclusive += 1;
+            }
+        }
+        if let Some(current) = self.stack.last() {
+            profile
+                .functions
+                .entry(current.clone())
+                .or_default()
+                .exclusive += 1;
+        }
+        *profile.stacks.entry(self.stack.clone()).or_default() += 1;
+    }
+
+    // Follows calls and returns made by the instruction that was just executed.
+    pub fn after_step(&mut self, vm: &VM) {
+        let depth = vm.call_stack().len();
+
+        while self.depth < depth {
+            let function = vm.program().label_at(vm.ptr()).unwrap_or(MAIN).to_string();
+            self.profile
+                .functions
+                .entry(function.clone())
+                .or_default()
+                .calls += 1;
+            self.stack.push(function);
+            self.depth += 1;
+        }
+        while self.depth > depth {
+            self.stack.pop();
+            self.depth -= 1;
+        }
+    }
+
+    pub fn run(&mut self, vm: &mut VM) -> Result<Outcome, VMError> {
+        self.depth = vm.call_stack().len();
+        loop {
+            self.before_step(vm);
+            let result = vm.step();
+            self.after_step(vm);
+
+            if let Some(outcome) = result? {
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+impl Profile {
+    pub fn report(&self, vm: &VM) -> String {
+        let mut out = format!("Instructions executed: {}\n", self.total);
+
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        out.push_str("\nFunctions:\n");
+        out.push_str(&format!(
+            "{:>12} {:>12} {:>8}  function\n",
+            "inclusive", "exclusive", "calls"
+        ));
+        for (name, function) in functions {
+            out.push_str(&format!(
+                "{:>12} {:>12} {:>8}  {}\n",
+                function.inclusive, function.exclusive, function.calls, name
+            ));
+        }
+
+        let mut opcodes = self.by_opcode.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        out.push_str("\nOpcodes:\n");
+        out.push_str(&format!("{:>12}  opcode\n", "count"));
+        for (opcode, count) in opcodes {
+            out.push_str(&format!("{:>12}  {}\n", count, opcode));
+        }
+
+        let mut instructions = self.by_instruction.iter().collect::<Vec<_>>();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        out.push_str("\nInstructions:\n");
+        out.push_str(&format!("{:>12} {:>6}  instruction\n", "count", "index"));
+        for (&index, count) in instructions {
+            let instr = &vm.program().instructions[index];
+            match vm.program().location(index) {
+                Some(location) => out.push_str(&format!(
+                    "{:>12} {:>6}  {} ({})\n",
+                    count, index, instr, location
+                )),
+                None => out.push_str(&format!("{:>12} {:>6}  {}\n", count, index, instr)),
+            }
+        }
+
+        out
+    }
+
+    // Renders the samples in the collapsed stack format used by flamegraph tools.
+    pub fn collapsed(&self) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| format!("{} {}", stack.join(";"), count))
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+
+    fn profile(source: &str) -> Profile {
+        let mut vm = VM::new(Program::from_source(source).unwrap());
+        let mut profiler = Profiler::new();
+        profiler.run(&mut vm).unwrap();
+        profiler.profile().clone()
+    }
+
+    #[test]
+    fn functions_are_credited_inclusively_and_exclusively() {
+        let profile = profile(
+            "
+            CALL f
+            EXIT_IMM 0
+            LABEL f
+            CALL g
+            RET
+            LABEL g
+            PUSHI 1
+            POP
+            PUSHI 1
+            POP
+            RET
+        ",
+        );
+        assert_eq!(profile.total, 9);
+        let function = |name: &str| profile.functions[name].clone();
+        let expected = |calls, inclusive, exclusive| FunctionProfile {
+            calls,
+            inclusive,
+            exclusive,
+        };
+        assert_eq!(function("<main>"), expected(0, 9, 2));
+        assert_eq!(function("f"), expected(1, 7, 2));
+        assert_eq!(function("g"), expected(1, 5, 5));
+        assert_eq!(profile.by_opcode["PUSHI"], 2);
+        assert_eq!(profile.collapsed(), "<main> 2\n<main>;f 2\n<main>;f;g 5\n");
+    }
+
+    #[test]
+    fn recursion_is_counted_once_inclusively() {
+        let profile = profile(
+            "
+            PUSHI 2
+            CALL f
+            EXIT_IMM 0
+            LABEL f
+            SUBI 1
+            DUP
+            JZ done
+            CALL f
+            LABEL done
+            RET
+        ",
+        );
+        let f = &profile.functions["f"];
+        assert_eq!(f.calls, 2);
+        assert_eq!(f.inclusive, f.exclusive);
+        assert_eq!(profile.total, f.inclusive + 3);
+    }
+}
@@ -2,12 +2,12 @@ use std::collections::HashMap;
 use std::fmt::Display;
 
 use crate::opcodes::*;
-use crate::parse;
+use crate::{parse, parse_with_debug_info};
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
     Exit,              // 0x00 EXIT
-    ExitImmediate(u8), // 0x01 EXIT [imm]
+    ExitImmediate(u8), // 0x01 EXIT_IMM [imm]
 
     PushInt(i64),       // 0x10 PUSHI [imm]
     PushUInt(u64),      // 0x11 PUSHU [imm]
@@ -17,6 +17,14 @@ pub enum Instruction {
     Pop,                // 0x15 POP
     Dup,                // 0x16 DUP
     Swap,               // 0x17 SWAP
+    PushNil,            // 0x18 PUSHN
+    Over,               // 0x19 OVER
+    Rot,                // 0x1a ROT
+    Pick(u8),           // 0x1b PICK [imm]
+    Roll(u8),           // 0x1c ROLL [imm]
+    Drop(u8),           // 0x1d DROP [imm]
+    Dup2,               // 0x1e DUP2
+    Depth,              // 0x1f DEPTH
 
     Add,       // 0x20 ADD
     AddI(i64), // 0x21 ADDI [imm]
@@ -42,12 +50,14 @@ pub enum Instruction {
     ExpU(u64), // 0x35 EXPU [imm]
     ExpF(f64), // 0x36 EXPF [imm]
 
-    Load,             // 0x40 LOAD
-    LoadImm(String),  // 0x41 LOAD [imm]
-    Store,            // 0x42 STORE
-    StoreImm(String), // 0x43 STORE [imm]
-    Free,             // 0x44 FREE
-    FreeImm(String),  // 0x45 FREE [imm]
+    Load,               // 0x40 LOAD
+    LoadImm(String),    // 0x41 LOAD_IMM [imm]
+    Store,              // 0x42 STORE
+    StoreImm(String),   // 0x43 STORE_IMM [imm]
+    Free,               // 0x44 FREE
+    FreeImm(String),    // 0x45 FREE_IMM [imm]
+    LoadNil,            // 0x46 LOADN
+    LoadNilImm(String), // 0x47 LOADN_IMM [imm]
 
     CmpEqual,        // 0x50 CMPEQ
     CmpNotEqual,     // 0x51 CMPNE
@@ -56,18 +66,59 @@ pub enum Instruction {
     CmpGreaterEqual, // 0x54 CMPGE
     CmpLessEqual,    // 0x55 CMPLE
 
-    Jump(String),       // 0x60 JMP [label]
-    JumpIf(String),     // 0x61 JMPIF [label]
-    Call(String),       // 0x62 CALL [label]
-    CallNative(String), // 0x63 CALLNATIVE [label]
-    Ret,                // 0x64 RET
+    Jump(String),         // 0x60 JMP [label]
+    JumpIf(String),       // 0x61 JMPIF [label]
+    Call(String),         // 0x62 CALL [label]
+    CallNative(String),   // 0x63 CALLNATIVE [label]
+    Ret,                  // 0x64 RET
+    Yield,                // 0x65 YIELD
+    Spawn(String),        // 0x66 SPAWN [label]
+    Resume,               // 0x67 RESUME
+    Done,                 // 0x68 DONE
+    Try(String),          // 0x69 TRY [label]
+    EndTry,               // 0x6a ENDTRY
+    Throw,                // 0x6b THROW
+    PushFunction(String), // 0x6c PUSHFN [label]
+    PushNative(String),   // 0x6d PUSHNATIVE [name]
+    CallValue,            // 0x6e CALLV
+    TailCall(String),     // 0x6f TAILCALL [label]
+
+    MapNew,    // 0x80 MAPNEW
+    MapGet,    // 0x81 MAPGET
+    MapSet,    // 0x82 MAPSET
+    MapHas,    // 0x83 MAPHAS
+    MapDelete, // 0x84 MAPDEL
+    MapKeys,   // 0x85 MAPKEYS
+    MapLen,    // 0x86 MAPLEN
+
+    Box,    // 0x90 BOX
+    Unbox,  // 0x91 UNBOX
+    SetRef, // 0x92 SETREF
+
+    MakeClosure(u8), // 0xa0 MAKECLOSURE [imm]
+    GetUpvalue(u8),  // 0xa1 GETUPVAL [imm]
+    SetUpvalue(u8),  // 0xa2 SETUPVAL [imm]
+
+    JumpTable(String, Vec<String>), // 0xb0 JMPTABLE [label] [label...]
+    JumpValue,                      // 0xb1 JMPV
+    JumpIfNot(String),              // 0xb2 JMPIFNOT [label]
+    JumpEqual(String),              // 0xb3 JEQ [label]
+    JumpNotEqual(String),           // 0xb4 JNE [label]
+    JumpGreaterThan(String),        // 0xb5 JGT [label]
+    JumpLessThan(String),           // 0xb6 JLT [label]
+    JumpGreaterEqual(String),       // 0xb7 JGE [label]
+    JumpLessEqual(String),          // 0xb8 JLE [label]
+    JumpZero(String),               // 0xb9 JZ [label]
+    JumpNotZero(String),            // 0xba JNZ [label]
+    JumpNil(String),                // 0xbb JNIL [label]
+    JumpNotNil(String),             // 0xbc JNNIL [label]
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Instruction::Exit => write!(f, "EXIT"),
-            Instruction::ExitImmediate(imm) => write!(f, "EXIT {}", imm),
+            Instruction::ExitImmediate(imm) => write!(f, "EXIT_IMM {}", imm),
 
             Instruction::PushInt(imm) => write!(f, "PUSHI {}", imm),
             Instruction::PushUInt(imm) => write!(f, "PUSHU {}", imm),
@@ -77,6 +128,14 @@ impl Display for Instruction {
             Instruction::Pop => write!(f, "POP"),
             Instruction::Dup => write!(f, "DUP"),
             Instruction::Swap => write!(f, "SWAP"),
+            Instruction::PushNil => write!(f, "PUSHN"),
+            Instruction::Over => write!(f, "OVER"),
+            Instruction::Rot => write!(f, "ROT"),
+            Instruction::Pick(imm) => write!(f, "PICK {}", imm),
+            Instruction::Roll(imm) => write!(f, "ROLL {}", imm),
+            Instruction::Drop(imm) => write!(f, "DROP {}", imm),
+            Instruction::Dup2 => write!(f, "DUP2"),
+            Instruction::Depth => write!(f, "DEPTH"),
 
             Instruction::Add => write!(f, "ADD"),
             Instruction::AddI(imm) => write!(f, "ADDI {}", imm),
@@ -102,11 +161,13 @@ impl Display for Instruction {
             Instruction::ExpU(imm) => write!(f, "EXPU {}", imm),
             Instruction::ExpF(imm) => write!(f, "EXPF {}", imm),
             Instruction::Load => write!(f, "LOAD"),
-            Instruction::LoadImm(imm) => write!(f, "LOAD {}", imm),
+            Instruction::LoadImm(imm) => write!(f, "LOAD_IMM {}", imm),
             Instruction::Store => write!(f, "STORE"),
-            Instruction::StoreImm(imm) => write!(f, "STORE {}", imm),
+            Instruction::StoreImm(imm) => write!(f, "STORE_IMM {}", imm),
             Instruction::Free => write!(f, "FREE"),
-            Instruction::FreeImm(imm) => write!(f, "FREE {}", imm),
+            Instruction::FreeImm(imm) => write!(f, "FREE_IMM {}", imm),
+            Instruction::LoadNil => write!(f, "LOADN"),
+            Instruction::LoadNilImm(name) => write!(f, "LOADN_IMM {}", name),
             Instruction::CmpEqual => write!(f, "CMPEQ"),
             Instruction::CmpNotEqual => write!(f, "CMPNE"),
             Instruction::CmpGreaterThan => write!(f, "CMPGT"),
@@ -118,20 +179,101 @@ impl Display for Instruction {
             Instruction::Call(label) => write!(f, "CALL {}", label),
             Instruction::CallNative(label) => write!(f, "CALLNATIVE {}", label),
             Instruction::Ret => write!(f, "RET"),
+            Instruction::Yield => write!(f, "YIELD"),
+            Instruction::Spawn(label) => write!(f, "SPAWN {}", label),
+            Instruction::Resume => write!(f, "RESUME"),
+            Instruction::Done => write!(f, "DONE"),
+            Instruction::Try(label) => write!(f, "TRY {}", label),
+            Instruction::EndTry => write!(f, "ENDTRY"),
+            Instruction::Throw => write!(f, "THROW"),
+            Instruction::PushFunction(label) => write!(f, "PUSHFN {}", label),
+            Instruction::PushNative(name) => write!(f, "PUSHNATIVE {}", name),
+            Instruction::CallValue => write!(f, "CALLV"),
+            Instruction::TailCall(label) => write!(f, "TAILCALL {}", label),
+
+            Instruction::MapNew => write!(f, "MAPNEW"),
+            Instruction::MapGet => write!(f, "MAPGET"),
+            Instruction::MapSet => write!(f, "MAPSET"),
+            Instruction::MapHas => write!(f, "MAPHAS"),
+            Instruction::MapDelete => write!(f, "MAPDEL"),
+            Instruction::MapKeys => write!(f, "MAPKEYS"),
+            Instruction::MapLen => write!(f, "MAPLEN"),
+
+            Instruction::Box => write!(f, "BOX"),
+            Instruction::Unbox => write!(f, "UNBOX"),
+            Instruction::SetRef => write!(f, "SETREF"),
+
+            Instruction::MakeClosure(imm) => write!(f, "MAKECLOSURE {}", imm),
+            Instruction::GetUpvalue(imm) => write!(f, "GETUPVAL {}", imm),
+            Instruction::SetUpvalue(imm) => write!(f, "SETUPVAL {}", imm),
+
+            Instruction::JumpTable(default, labels) => {
+                write!(f, "JMPTABLE {}", default)?;
+                for label in labels {
+                    write!(f, " {}", label)?;
+                }
+                Ok(())
+            }
+            Instruction::JumpValue => write!(f, "JMPV"),
+            Instruction::JumpIfNot(label) => write!(f, "JMPIFNOT {}", label),
+            Instruction::JumpEqual(label) => write!(f, "JEQ {}", label),
+            Instruction::JumpNotEqual(label) => write!(f, "JNE {}", label),
+            Instruction::JumpGreaterThan(label) => write!(f, "JGT {}", label),
+            Instruction::JumpLessThan(label) => write!(f, "JLT {}", label),
+            Instruction::JumpGreaterEqual(label) => write!(f, "JGE {}", label),
+            Instruction::JumpLessEqual(label) => write!(f, "JLE {}", label),
+            Instruction::JumpZero(label) => write!(f, "JZ {}", label),
+            Instruction::JumpNotZero(label) => write!(f, "JNZ {}", label),
+            Instruction::JumpNil(label) => write!(f, "JNIL {}", label),
+            Instruction::JumpNotNil(label) => write!(f, "JNNIL {}", label),
         }
     }
 }
 
 #[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub file: String,
+    pub lines: Vec<usize>,
+}
+
+impl DebugInfo {
+    pub fn line(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).copied()
+    }
+
+    // Finds the first instruction on or after the given source line.
+    pub fn index_for_line(&self, line: usize) -> Option<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| **l >= line)
+            .min_by_key(|(_, l)| **l)
+            .map(|(index, _)| index)
+    }
+}
+
+// The arity declared by a `FUNC` directive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Signature {
+    pub argc: u8,
+    pub retc: u8,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
     pub labels: HashMap<String, usize>,
+    // Signatures of functions declared with `FUNC`, by address.
+    pub functions: HashMap<usize, Signature>,
+    pub debug_info: Option<DebugInfo>,
 }
 
 impl Program {
     pub fn from_bytecode(source: Vec<u8>) -> Result<Self, String> {
         let mut instructions = Vec::new();
         let mut labels = HashMap::new();
+        let mut functions = HashMap::new();
+        let mut debug_info = None;
 
         let mut index = 0;
         while index < source.len() {
@@ -181,6 +323,26 @@ impl Program {
                 OP_POP => instructions.push(Instruction::Pop),
                 OP_DUP => instructions.push(Instruction::Dup),
                 OP_SWAP => instructions.push(Instruction::Swap),
+                OP_PUSH_NIL => instructions.push(Instruction::PushNil),
+                OP_OVER => instructions.push(Instruction::Over),
+                OP_ROT => instructions.push(Instruction::Rot),
+                OP_PICK => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::Pick(imm));
+                }
+                OP_ROLL => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::Roll(imm));
+                }
+                OP_DROP => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::Drop(imm));
+                }
+                OP_DUP2 => instructions.push(Instruction::Dup2),
+                OP_DEPTH => instructions.push(Instruction::Depth),
 
                 OP_ADD => instructions.push(Instruction::Add),
                 OP_ADD_I => {
@@ -318,6 +480,15 @@ impl Program {
                     index += str_len;
                     instructions.push(Instruction::FreeImm(imm));
                 }
+                OP_LOAD_NIL => instructions.push(Instruction::LoadNil),
+                OP_LOAD_NIL_IMM => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::LoadNilImm(imm));
+                }
 
                 OP_CMP_EQUAL => instructions.push(Instruction::CmpEqual),
                 OP_CMP_NOT_EQUAL => instructions.push(Instruction::CmpNotEqual),
@@ -359,6 +530,187 @@ impl Program {
                     instructions.push(Instruction::CallNative(imm));
                 }
                 OP_RET => instructions.push(Instruction::Ret),
+                OP_YIELD => instructions.push(Instruction::Yield),
+                OP_SPAWN => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::Spawn(imm));
+                }
+                OP_RESUME => instructions.push(Instruction::Resume),
+                OP_DONE => instructions.push(Instruction::Done),
+                OP_TRY => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::Try(imm));
+                }
+                OP_END_TRY => instructions.push(Instruction::EndTry),
+                OP_THROW => instructions.push(Instruction::Throw),
+                OP_PUSH_FUNCTION => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::PushFunction(imm));
+                }
+                OP_PUSH_NATIVE => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::PushNative(imm));
+                }
+                OP_CALL_VALUE => instructions.push(Instruction::CallValue),
+                OP_TAIL_CALL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::TailCall(imm));
+                }
+
+                OP_MAP_NEW => instructions.push(Instruction::MapNew),
+                OP_MAP_GET => instructions.push(Instruction::MapGet),
+                OP_MAP_SET => instructions.push(Instruction::MapSet),
+                OP_MAP_HAS => instructions.push(Instruction::MapHas),
+                OP_MAP_DELETE => instructions.push(Instruction::MapDelete),
+                OP_MAP_KEYS => instructions.push(Instruction::MapKeys),
+                OP_MAP_LEN => instructions.push(Instruction::MapLen),
+
+                OP_BOX => instructions.push(Instruction::Box),
+                OP_UNBOX => instructions.push(Instruction::Unbox),
+                OP_SET_REF => instructions.push(Instruction::SetRef),
+
+                OP_MAKE_CLOSURE => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::MakeClosure(imm));
+                }
+                OP_GET_UPVALUE => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::GetUpvalue(imm));
+                }
+                OP_SET_UPVALUE => {
+                    let imm = source[index];
+                    index += 1;
+                    instructions.push(Instruction::SetUpvalue(imm));
+                }
+
+                OP_JUMP_TABLE => {
+                    let count_bytes = &source[index..index + 4];
+                    let count = u32::from_le_bytes(count_bytes.try_into().unwrap()) as usize;
+                    index += 4;
+                    let mut labels = Vec::with_capacity(count + 1);
+                    for _ in 0..=count {
+                        let str_len = source[index] as usize;
+                        index += 1;
+                        let str_bytes = &source[index..index + str_len];
+                        labels.push(
+                            String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?,
+                        );
+                        index += str_len;
+                    }
+                    let default = labels.remove(0);
+                    instructions.push(Instruction::JumpTable(default, labels));
+                }
+                OP_JUMP_VALUE => instructions.push(Instruction::JumpValue),
+                OP_JUMP_IF_NOT => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpIfNot(imm));
+                }
+                OP_JUMP_EQUAL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpEqual(imm));
+                }
+                OP_JUMP_NOT_EQUAL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpNotEqual(imm));
+                }
+                OP_JUMP_GREATER_THAN => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpGreaterThan(imm));
+                }
+                OP_JUMP_LESS_THAN => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpLessThan(imm));
+                }
+                OP_JUMP_GREATER_EQUAL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpGreaterEqual(imm));
+                }
+                OP_JUMP_LESS_EQUAL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpLessEqual(imm));
+                }
+                OP_JUMP_ZERO => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpZero(imm));
+                }
+                OP_JUMP_NOT_ZERO => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpNotZero(imm));
+                }
+                OP_JUMP_NIL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpNil(imm));
+                }
+                OP_JUMP_NOT_NIL => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let imm = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    instructions.push(Instruction::JumpNotNil(imm));
+                }
 
                 OP_LABEL => {
                     let str_len = source[index] as usize;
@@ -368,6 +720,38 @@ impl Program {
                     index += str_len;
                     labels.insert(label, instructions.len());
                 }
+                OP_FUNC => {
+                    let str_len = source[index] as usize;
+                    index += 1;
+                    let str_bytes = &source[index..index + str_len];
+                    let label = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += str_len;
+                    let argc = source[index];
+                    let retc = source[index + 1];
+                    index += 2;
+                    labels.insert(label, instructions.len());
+                    functions.insert(instructions.len(), Signature { argc, retc });
+                }
+                OP_DEBUG_INFO => {
+                    let len_bytes = &source[index..index + 4];
+                    let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+                    index += 4;
+                    let str_bytes = &source[index..index + len];
+                    let file = String::from_utf8(str_bytes.to_vec()).map_err(|e| e.to_string())?;
+                    index += len;
+
+                    let count_bytes = &source[index..index + 4];
+                    let count = u32::from_le_bytes(count_bytes.try_into().unwrap()) as usize;
+                    index += 4;
+                    let mut lines = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let line_bytes = &source[index..index + 4];
+                        lines.push(u32::from_le_bytes(line_bytes.try_into().unwrap()) as usize);
+                        index += 4;
+                    }
+
+                    debug_info = Some(DebugInfo { file, lines });
+                }
 
                 _ => {
                     return Err(format!("Unknown opcode: 0x{:02X}", opcode));
@@ -376,14 +760,106 @@ impl Program {
         }
 
         Ok(Program {
-            instructions: instructions,
-            labels: labels,
+            instructions,
+            labels,
+            functions,
+            debug_info,
         })
     }
 
     pub fn from_source(source: &str) -> Result<Self, String> {
         let bytecode = parse(source)?;
 
-        return Self::from_bytecode(bytecode);
+        Self::from_bytecode(bytecode)
+    }
+
+    pub fn from_source_with_debug_info(source: &str, file: &str) -> Result<Self, String> {
+        let bytecode = parse_with_debug_info(source, file)?;
+
+        Self::from_bytecode(bytecode)
+    }
+
+    // Describes where an instruction came from as `file:line`, if debug info is available.
+    pub fn location(&self, index: usize) -> Option<String> {
+        let debug_info = self.debug_info.as_ref()?;
+        let line = debug_info.line(index)?;
+        Some(format!("{}:{}", debug_info.file, line))
+    }
+
+    // Finds the nearest label at or before the given instruction.
+    pub fn label_at(&self, index: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, addr)| **addr <= index)
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, _)| name.as_str())
+    }
+
+    // A stable FNV-1a hash of the instructions and labels, used to identify the program in
+    // snapshots. Debug info is left out since it does not affect execution.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        for instr in &self.instructions {
+            feed(instr.to_string().as_bytes());
+            feed(b"\n");
+        }
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort();
+        for (name, addr) in labels {
+            feed(format!("{}={}\n", name, addr).as_bytes());
+        }
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by_key(|(addr, _)| **addr);
+        for (addr, signature) in functions {
+            feed(format!("{}({},{})\n", addr, signature.argc, signature.retc).as_bytes());
+        }
+
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    const SOURCE: &str = "# setup\nPUSHI 1\n\nLABEL next\nSTORE_IMM x\nEXIT_IMM 0\n";
+
+    #[test]
+    fn debug_info_maps_instructions_to_lines() {
+        let program = Program::from_source_with_debug_info(SOURCE, "main.vvm").unwrap();
+        let debug_info = program.debug_info.as_ref().unwrap();
+        assert_eq!(debug_info.lines, [2, 5, 6]);
+        assert_eq!(program.location(1).as_deref(), Some("main.vvm:5"));
+        assert_eq!(debug_info.index_for_line(3), Some(1));
+        assert_eq!(debug_info.index_for_line(7), None);
+    }
+
+    #[test]
+    fn program_without_debug_info_has_no_locations() {
+        let program = Program::from_source(SOURCE).unwrap();
+        assert!(program.debug_info.is_none());
+        assert_eq!(program.location(0), None);
+    }
+
+    #[test]
+    fn disassembly_reassembles() {
+        let program = Program::from_source_with_debug_info(SOURCE, "main.vvm").unwrap();
+        let source = disasm(program.clone());
+        assert!(source.contains("STORE_IMM x"));
+        assert!(source.contains("EXIT_IMM 0"));
+        let text = |program: &Program| {
+            let lines = program.instructions.iter().map(|i| i.to_string());
+            lines.collect::<Vec<_>>()
+        };
+        let reassembled = Program::from_source(&source).unwrap();
+        assert_eq!(text(&reassembled), text(&program));
     }
 }
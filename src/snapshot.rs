@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::heap::Heap;
+use crate::vm::{CallFrame, Coroutine, CoroutineState, Handler};
+use crate::{Function, MapKey, VM, Value};
+
+const MAGIC: &[u8] = b"VVMS";
+const VERSION: u8 = 1;
+
+const TAG_INT: u8 = 0x00;
+const TAG_UINT: u8 = 0x01;
+const TAG_FLOAT: u8 = 0x02;
+const TAG_BOOL: u8 = 0x03;
+const TAG_STRING: u8 = 0x04;
+const TAG_ARRAY: u8 = 0x05;
+const TAG_COROUTINE: u8 = 0x06;
+const TAG_MAP: u8 = 0x07;
+const TAG_NIL: u8 = 0x08;
+const TAG_REF: u8 = 0x09;
+const TAG_FUNCTION: u8 = 0x0a;
+const TAG_NATIVE: u8 = 0x0b;
+const TAG_CLOSURE: u8 = 0x0c;
+
+const STATE_SUSPENDED: u8 = 0x00;
+const STATE_RUNNING: u8 = 0x01;
+const STATE_DONE: u8 = 0x02;
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_u32(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_addrs(out: &mut Vec<u8>, addrs: &[usize]) {
+    write_u32(out, addrs.len());
+    for &addr in addrs {
+        write_u64(out, addr as u64);
+    }
+}
+
+fn write_closure(out: &mut Vec<u8>, closure: Option<usize>) {
+    match closure {
+        Some(env) => {
+            out.push(1);
+            write_u64(out, env as u64);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_frames(out: &mut Vec<u8>, frames: &[CallFrame]) {
+    write_u32(out, frames.len());
+    for frame in frames {
+        write_u64(out, frame.return_addr as u64);
+        write_closure(out, frame.closure);
+        write_u64(out, frame.base as u64);
+        match frame.retc {
+            Some(retc) => out.extend_from_slice(&[1, retc]),
+            None => out.push(0),
+        }
+    }
+}
+
+fn write_handlers(out: &mut Vec<u8>, handlers: &[Handler]) {
+    write_u32(out, handlers.len());
+    for handler in handlers {
+        write_u64(out, handler.target as u64);
+        write_u64(out, handler.call_depth as u64);
+        write_u64(out, handler.stack_depth as u64);
+        write_closure(out, handler.closure);
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(v) => {
+            out.push(TAG_INT);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::UInt(v) => {
+            out.push(TAG_UINT);
+            write_u64(out, *v);
+        }
+        Value::Float(v) => {
+            out.push(TAG_FLOAT);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::Bool(v) => {
+            out.push(TAG_BOOL);
+            out.push(*v as u8);
+        }
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            write_string(out, s);
+        }
+        Value::Array(items) => {
+            out.push(TAG_ARRAY);
+            write_values(out, items);
+        }
+        Value::Coroutine(id) => {
+            out.push(TAG_COROUTINE);
+            write_u64(out, *id as u64);
+        }
+        Value::Nil => out.push(TAG_NIL),
+        Value::Ref(id) => {
+            out.push(TAG_REF);
+            write_u64(out, *id as u64);
+        }
+        Value::Function(Function::Address(addr)) => {
+            out.push(TAG_FUNCTION);
+            write_u64(out, *addr as u64);
+        }
+        Value::Function(Function::Native(name)) => {
+            out.push(TAG_NATIVE);
+            write_string(out, name);
+        }
+        Value::Function(Function::Closure { address, env }) => {
+            out.push(TAG_CLOSURE);
+            write_u64(out, *address as u64);
+            write_u64(out, *env as u64);
+        }
+        Value::Map(entries) => {
+            out.push(TAG_MAP);
+            write_u32(out, entries.len());
+            for (key, value) in entries {
+                write_value(out, &key.to_value());
+                write_value(out, value);
+            }
+        }
+    }
+}
+
+fn write_values(out: &mut Vec<u8>, values: &[Value]) {
+    write_u32(out, values.len());
+    for value in values {
+        write_value(out, value);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self
+            .index
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| "Truncated snapshot".to_string())?;
+        let bytes = &self.data[self.index..end];
+        self.index = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn addrs(&mut self) -> Result<Vec<usize>, String> {
+        let count = self.u32()?;
+        (0..count).map(|_| Ok(self.u64()? as usize)).collect()
+    }
+
+    fn closure(&mut self) -> Result<Option<usize>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u64()? as usize)),
+        }
+    }
+
+    fn frames(&mut self) -> Result<Vec<CallFrame>, String> {
+        let count = self.u32()?;
+        (0..count)
+            .map(|_| {
+                Ok(CallFrame {
+                    return_addr: self.u64()? as usize,
+                    closure: self.closure()?,
+                    base: self.u64()? as usize,
+                    retc: match self.u8()? {
+                        0 => None,
+                        _ => Some(self.u8()?),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn handlers(&mut self) -> Result<Vec<Handler>, String> {
+        let count = self.u32()?;
+        (0..count)
+            .map(|_| {
+                Ok(Handler {
+                    target: self.u64()? as usize,
+                    call_depth: self.u64()? as usize,
+                    stack_depth: self.u64()? as usize,
+                    closure: self.closure()?,
+                })
+            })
+            .collect()
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.u8()? {
+            TAG_INT => Ok(Value::Int(i64::from_le_bytes(
+                self.bytes(8)?.try_into().unwrap(),
+            ))),
+            TAG_UINT => Ok(Value::UInt(self.u64()?)),
+            TAG_FLOAT => Ok(Value::Float(f64::from_le_bytes(
+                self.bytes(8)?.try_into().unwrap(),
+            ))),
+            TAG_BOOL => Ok(Value::Bool(self.u8()? != 0)),
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_ARRAY => Ok(Value::Array(self.values()?)),
+            TAG_COROUTINE => Ok(Value::Coroutine(self.u64()? as usize)),
+            TAG_NIL => Ok(Value::Nil),
+            TAG_REF => Ok(Value::Ref(self.u64()? as usize)),
+            TAG_FUNCTION => Ok(Value::Function(Function::Address(self.u64()? as usize))),
+            TAG_NATIVE => Ok(Value::Function(Function::Native(self.string()?))),
+            TAG_CLOSURE => Ok(Value::Function(Function::Closure {
+                address: self.u64()? as usize,
+                env: self.u64()? as usize,
+            })),
+            TAG_MAP => {
+                let mut entries = BTreeMap::new();
+                for _ in 0..self.u32()? {
+                    let key = MapKey::from_value(self.value()?)?;
+                    entries.insert(key, self.value()?);
+                }
+                Ok(Value::Map(entries))
+            }
+            tag => Err(format!("Unknown value tag {:#04x} in snapshot", tag)),
+        }
+    }
+
+    fn values(&mut self) -> Result<Vec<Value>, String> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.value()).collect()
+    }
+}
+
+impl VM {
+    // Serializes the execution state of the VM. Host configuration such as fuel, limits and
+    // observers is not included, and native handlers are only recorded by name.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_u64(&mut out, self.program.fingerprint());
+
+        let mut natives = self.native_names().collect::<Vec<_>>();
+        natives.sort();
+        write_u32(&mut out, natives.len());
+        for name in natives {
+            write_string(&mut out, name);
+        }
+
+        write_u64(&mut out, self.ptr as u64);
+        write_frames(&mut out, &self.call_stack);
+        write_closure(&mut out, self.closure);
+        write_values(&mut out, &self.data_stack);
+        write_handlers(&mut out, &self.handlers);
+
+        let mut memory = self.memory.iter().collect::<Vec<_>>();
+        memory.sort_by(|a, b| a.0.cmp(b.0));
+        write_u32(&mut out, memory.len());
+        for (name, value) in memory {
+            write_string(&mut out, name);
+            write_value(&mut out, value);
+        }
+
+        write_u32(&mut out, self.coroutines.len());
+        for coroutine in &self.coroutines {
+            out.push(match coroutine.state {
+                CoroutineState::Suspended => STATE_SUSPENDED,
+                CoroutineState::Running => STATE_RUNNING,
+                CoroutineState::Done => STATE_DONE,
+            });
+            write_u64(&mut out, coroutine.ptr as u64);
+            write_frames(&mut out, &coroutine.call_stack);
+            write_closure(&mut out, coroutine.closure);
+            write_values(&mut out, &coroutine.data_stack);
+            write_handlers(&mut out, &coroutine.handlers);
+        }
+        write_addrs(&mut out, &self.active);
+
+        let slots = self.heap.slots();
+        write_u32(&mut out, slots.len());
+        for slot in slots {
+            match slot {
+                Some(value) => {
+                    out.push(1);
+                    write_value(&mut out, value);
+                }
+                None => out.push(0),
+            }
+        }
+
+        out
+    }
+
+    // Replaces the execution state of the VM with a snapshot. The VM must have been created
+    // from the same program, with every native handler the snapshot was taken with registered.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = Reader { data, index: 0 };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err("Not a vVM snapshot".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("Unsupported snapshot version {}", version));
+        }
+        if reader.u64()? != self.program.fingerprint() {
+            return Err("Snapshot was taken from a different program".to_string());
+        }
+
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            if !self.native_handlers.contains_key(&name) {
+                return Err(format!(
+                    "Snapshot requires native handler '{}', which is not registered",
+                    name
+                ));
+            }
+        }
+
+        let ptr = reader.u64()? as usize;
+        let call_stack = reader.frames()?;
+        let closure = reader.closure()?;
+        let data_stack = reader.values()?;
+        let handlers = reader.handlers()?;
+
+        let mut memory = HashMap::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            memory.insert(name, reader.value()?);
+        }
+
+        let mut coroutines = Vec::new();
+        for _ in 0..reader.u32()? {
+            let state = match reader.u8()? {
+                STATE_SUSPENDED => CoroutineState::Suspended,
+                STATE_RUNNING => CoroutineState::Running,
+                STATE_DONE => CoroutineState::Done,
+                state => return Err(format!("Unknown coroutine state {} in snapshot", state)),
+            };
+            coroutines.push(Coroutine {
+                state,
+                ptr: reader.u64()? as usize,
+                call_stack: reader.frames()?,
+                closure: reader.closure()?,
+                data_stack: reader.values()?,
+                handlers: reader.handlers()?,
+            });
+        }
+        let active = reader.addrs()?;
+        if active.iter().any(|&id| id >= coroutines.len()) {
+            return Err("Invalid coroutine in snapshot".to_string());
+        }
+
+        let mut slots = Vec::new();
+        for _ in 0..reader.u32()? {
+            slots.push(match reader.u8()? {
+                0 => None,
+                _ => Some(reader.value()?),
+            });
+        }
+
+        if reader.index != data.len() {
+            return Err("Trailing data after snapshot".to_string());
+        }
+
+        self.ptr = ptr;
+        self.call_stack = call_stack;
+        self.closure = closure;
+        self.data_stack = data_stack;
+        self.handlers = handlers;
+        self.memory = memory;
+        self.coroutines = coroutines;
+        self.free_coroutines = Vec::new();
+        self.active = active;
+        self.heap = Heap::from_slots(slots);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outcome, Program};
+
+    const SOURCE: &str = "
+        MAPNEW
+        PUSHS k
+        PUSHI 1
+        MAPSET
+        BOX
+        STORE_IMM m
+        SPAWN gen
+        STORE_IMM g
+        CALL f
+        EXIT_IMM 0
+        LABEL f
+        LOAD_IMM g
+        RESUME
+        YIELD
+        LOAD_IMM g
+        RESUME
+        LOAD_IMM m
+        UNBOX
+        PUSHS k
+        MAPGET
+        ADD
+        RET
+        LABEL gen
+        PUSHI 10
+        YIELD
+        PUSHI 20
+        YIELD
+    ";
+
+    fn vm(source: &str) -> VM {
+        VM::new(Program::from_source(source).unwrap())
+    }
+
+    // A snapshot taken while `f` is running and the coroutine is suspended.
+    fn paused() -> (VM, Vec<u8>) {
+        let mut vm = vm(SOURCE);
+        assert_eq!(vm.run().unwrap(), Outcome::Yielded(Value::Int(10)));
+        let snapshot = vm.snapshot();
+        (vm, snapshot)
+    }
+
+    #[test]
+    fn restored_vm_continues_where_snapshot_was_taken() {
+        let (mut original, snapshot) = paused();
+        let mut restored = vm(SOURCE);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+
+        assert_eq!(original.resume().unwrap(), Outcome::Exited(0));
+        assert_eq!(restored.resume().unwrap(), Outcome::Exited(0));
+        assert_eq!(restored.data_stack(), [Value::Int(21)]);
+        assert_eq!(restored.data_stack(), original.data_stack());
+    }
+
+    #[test]
+    fn fingerprint_identifies_program() {
+        let program = Program::from_source(SOURCE).unwrap();
+        assert_eq!(
+            program.fingerprint(),
+            Program::from_source(SOURCE).unwrap().fingerprint()
+        );
+        let other = Program::from_source_with_debug_info(SOURCE, "other.vvm").unwrap();
+        assert_eq!(program.fingerprint(), other.fingerprint());
+        let changed = Program::from_source(&SOURCE.replace("PUSHI 20", "PUSHI 21")).unwrap();
+        assert_ne!(program.fingerprint(), changed.fingerprint());
+    }
+
+    #[test]
+    fn invalid_snapshots_are_rejected() {
+        let (_, snapshot) = paused();
+        let restore = |data: &[u8]| vm(SOURCE).restore(data).unwrap_err();
+
+        assert_eq!(restore(b"junk"), "Not a vVM snapshot");
+        assert_eq!(
+            restore(&snapshot[..snapshot.len() - 1]),
+            "Truncated snapshot"
+        );
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert_eq!(restore(&trailing), "Trailing data after snapshot");
+        let mut version = snapshot.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            restore(&version),
+            format!("Unsupported snapshot version {}", VERSION + 1)
+        );
+        assert_eq!(
+            vm("PUSHI 1").restore(&snapshot).unwrap_err(),
+            "Snapshot was taken from a different program"
+        );
+    }
+
+    #[test]
+    fn restore_requires_native_handlers() {
+        let mut original = vm("PUSHI 1");
+        original.register_native_handler("print", |_| {});
+        let snapshot = original.snapshot();
+        assert_eq!(
+            vm("PUSHI 1").restore(&snapshot).unwrap_err(),
+            "Snapshot requires native handler 'print', which is not registered"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::Write;
+use std::ops::Range;
+
+use crate::{Instruction, Observer, VM, VMError, Value};
+
+// Writes one line per executed instruction, with the top of the stack before and after it.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    depth: usize,
+    range: Option<Range<usize>>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W, depth: usize, range: Option<Range<usize>>) -> Self {
+        TraceWriter { out, depth, range }
+    }
+
+    fn traces(&self, index: usize) -> bool {
+        match &self.range {
+            Some(range) => range.contains(&index),
+            None => true,
+        }
+    }
+
+    fn top<'a>(&self, vm: &'a VM) -> &'a [Value] {
+        let stack = vm.data_stack();
+        &stack[stack.len().saturating_sub(self.depth)..]
+    }
+}
+
+impl<W: Write> Observer for TraceWriter<W> {
+    fn before_instruction(&mut self, vm: &VM, index: usize, instr: &Instruction) {
+        if !self.traces(index) {
+            return;
+        }
+        let top = self.top(vm);
+        write!(
+            self.out,
+            "{:>6}  {:<24} {:?}",
+            index,
+            instr.to_string(),
+            top
+        )
+        .expect("Failed to write trace");
+    }
+
+    fn after_instruction(&mut self, vm: &VM, index: usize, _instr: &Instruction) {
+        if !self.traces(index) {
+            return;
+        }
+        let top = self.top(vm);
+        writeln!(self.out, " -> {:?}", top).expect("Failed to write trace");
+    }
+
+    fn on_error(&mut self, vm: &VM, error: &VMError) {
+        if !self.traces(vm.ptr()) {
+            return;
+        }
+        writeln!(self.out, " -> error: {}", error.message).expect("Failed to write trace");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::Program;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(source: &str, depth: usize, range: Option<Range<usize>>) -> String {
+        let buffer = Buffer::default();
+        let mut vm = VM::new(Program::from_source(source).unwrap());
+        vm.add_observer(TraceWriter::new(buffer.clone(), depth, range));
+        let _ = vm.run();
+        String::from_utf8(buffer.0.take()).unwrap()
+    }
+
+    #[test]
+    fn traces_each_instruction_with_top_of_stack() {
+        let trace = trace("PUSHI 1\nPUSHI 2\nADD", 1, None);
+        let lines = trace.lines().map(str::trim_end).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "     0  PUSHI 1                  [] -> [Int(1)]",
+                "     1  PUSHI 2                  [Int(1)] -> [Int(2)]",
+                "     2  ADD                      [Int(2)] -> [Int(3)]",
+            ]
+        );
+    }
+
+    #[test]
+    fn traces_only_range_and_errors() {
+        let trace = trace("PUSHI 1\nPOP\nPOP", 0, Some(1..3));
+        let lines = trace.lines().map(str::trim_end).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "     1  POP                      [] -> []",
+                "     2  POP                      [] -> error: Stack underflow",
+            ]
+        );
+    }
+}
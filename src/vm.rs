@@ -1,13 +1,63 @@
-use crate::{Instruction, Program, optimise};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::rc::Rc;
+
+use crate::heap::{Heap, refs};
+use crate::{GcStats, Instruction, Program, Signature, optimise};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
+    Nil,
     Int(i64),
     UInt(u64),
     Float(f64),
     Bool(bool),
     String(String),
     Array(Vec<Value>),
+    Coroutine(usize),
+    Map(BTreeMap<MapKey, Value>),
+    Ref(usize),
+    Function(Function),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+    Address(usize),
+    Native(String),
+    // `env` is a heap reference to the array of captured values.
+    Closure { address: usize, env: usize },
+}
+
+// The values that can be used as map keys. Keys of different types sort by type first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MapKey {
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    String(String),
+}
+
+impl MapKey {
+    pub fn from_value(value: Value) -> Result<MapKey, String> {
+        match value {
+            Value::Int(v) => Ok(MapKey::Int(v)),
+            Value::UInt(v) => Ok(MapKey::UInt(v)),
+            Value::Bool(v) => Ok(MapKey::Bool(v)),
+            Value::String(v) => Ok(MapKey::String(v)),
+            _ => Err("Map keys must be integers, booleans or strings".to_string()),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Int(v) => Value::Int(*v),
+            MapKey::UInt(v) => Value::UInt(*v),
+            MapKey::Bool(v) => Value::Bool(*v),
+            MapKey::String(v) => Value::String(v.clone()),
+        }
+    }
 }
 
 impl Value {
@@ -85,6 +135,28 @@ impl Value {
         }
     }
 
+    // Approximate size of the value in bytes, used to enforce `VmLimits::max_value_bytes`.
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Int(_)
+            | Value::UInt(_)
+            | Value::Float(_)
+            | Value::Coroutine(_)
+            | Value::Ref(_)
+            | Value::Function(Function::Address(_)) => 8,
+            Value::Function(Function::Native(name)) => name.len(),
+            Value::Function(Function::Closure { .. }) => 16,
+            Value::Nil => 0,
+            Value::Bool(_) => 1,
+            Value::String(s) => s.len(),
+            Value::Array(items) => items.iter().map(Value::size).sum(),
+            Value::Map(entries) => entries
+                .iter()
+                .map(|(key, value)| key.to_value().size() + value.size())
+                .sum(),
+        }
+    }
+
     pub fn exp(&self, other: &Value) -> Result<Value, String> {
         match (self, other) {
             (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a.pow(*b as u32))),
@@ -95,13 +167,159 @@ impl Value {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Finished,
+    Exited(i32),
+    OutOfFuel,
+    Yielded(Value),
+    Paused,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub index: usize,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let function = self.function.as_deref().unwrap_or("<main>");
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => {
+                write!(
+                    f,
+                    "at {} (instruction {}, {}:{})",
+                    function, self.index, file, line
+                )
+            }
+            _ => write!(f, "at {} (instruction {})", function, self.index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VMError {
+    pub message: String,
+    pub trace: Vec<Frame>,
+}
+
+impl Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n    {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+// Receives callbacks as the VM executes. Every method defaults to doing nothing, so
+// observers only implement the events they are interested in.
+#[allow(unused_variables)]
+pub trait Observer {
+    fn before_instruction(&mut self, vm: &VM, index: usize, instr: &Instruction) {}
+    fn after_instruction(&mut self, vm: &VM, index: usize, instr: &Instruction) {}
+    fn on_call(&mut self, vm: &VM, from: usize, target: usize) {}
+    fn on_return(&mut self, vm: &VM, from: usize, target: usize) {}
+    fn on_native(&mut self, vm: &VM, name: &str) {}
+    fn on_store(&mut self, vm: &VM, name: &str, value: &Value) {}
+    fn on_free(&mut self, vm: &VM, name: &str) {}
+    fn on_error(&mut self, vm: &VM, error: &VMError) {}
+}
+
+// Bounds on the resources a program may use. `None` leaves a resource unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmLimits {
+    pub max_stack_depth: Option<usize>,
+    pub max_call_depth: Option<usize>,
+    pub max_globals: Option<usize>,
+    pub max_value_bytes: Option<usize>,
+}
+
+// Pushed by CALL. `closure` is the environment of the caller, restored by RET. `base` is the
+// depth of the data stack below the callee's arguments, which the callee cannot pop past, and
+// `retc` is the number of results it must leave above it if it was declared with `FUNC`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub return_addr: usize,
+    pub closure: Option<usize>,
+    pub base: usize,
+    pub retc: Option<u8>,
+}
+
+// Installed by TRY. An error unwinds the stacks back to these depths and jumps to `target`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Handler {
+    pub(crate) target: usize,
+    pub(crate) call_depth: usize,
+    pub(crate) stack_depth: usize,
+    pub(crate) closure: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CoroutineState {
+    Suspended,
+    Running,
+    Done,
+}
+
+// While a coroutine is running, its slot holds the context of whoever resumed it, so
+// switching in either direction is a swap.
+#[derive(Debug)]
+pub(crate) struct Coroutine {
+    pub(crate) state: CoroutineState,
+    pub(crate) ptr: usize,
+    pub(crate) call_stack: Vec<CallFrame>,
+    pub(crate) closure: Option<usize>,
+    pub(crate) data_stack: Vec<Value>,
+    pub(crate) handlers: Vec<Handler>,
+}
+
+impl Coroutine {
+    fn new(state: CoroutineState, ptr: usize) -> Self {
+        Coroutine {
+            state,
+            ptr,
+            call_stack: Vec::new(),
+            closure: None,
+            data_stack: Vec::new(),
+            handlers: Vec::new(),
+        }
+    }
+}
+
+// Number of coroutine slots in use before SPAWN first collects garbage to reuse them.
+const COROUTINE_THRESHOLD: usize = 256;
+
+pub type NativeHandler = Rc<dyn Fn(&mut VM)>;
+
 pub struct VM {
-    program: Program,
-    ptr: usize,
-    call_stack: Vec<usize>,
-    data_stack: Vec<Value>,
-    memory: std::collections::HashMap<String, Value>,
-    native_handlers: std::collections::HashMap<String, fn(&mut VM)>,
+    pub(crate) program: Program,
+    pub(crate) ptr: usize,
+    pub(crate) call_stack: Vec<CallFrame>,
+    // Environment of the closure currently executing, if any.
+    pub(crate) closure: Option<usize>,
+    pub(crate) data_stack: Vec<Value>,
+    pub(crate) handlers: Vec<Handler>,
+    // Set by THROW and `VM::throw` so the error carries the thrown value.
+    thrown: Option<Value>,
+    pub(crate) memory: HashMap<String, Value>,
+    pub(crate) native_handlers: HashMap<String, NativeHandler>,
+    observers: Vec<Box<dyn Observer>>,
+    fuel: Option<u64>,
+    instruction_cost: u64,
+    native_costs: HashMap<String, u64>,
+    limits: VmLimits,
+    pub(crate) heap: Heap,
+    pub(crate) coroutines: Vec<Coroutine>,
+    // Slots of coroutines freed by the garbage collector, reused by SPAWN.
+    pub(crate) free_coroutines: Vec<usize>,
+    coroutine_threshold: usize,
+    // Coroutines currently running, innermost last.
+    pub(crate) active: Vec<usize>,
 }
 
 impl VM {
@@ -110,14 +328,144 @@ impl VM {
             program: optimise(&program),
             ptr: 0,
             call_stack: Vec::new(),
+            closure: None,
             data_stack: Vec::new(),
-            memory: std::collections::HashMap::new(),
-            native_handlers: std::collections::HashMap::new(),
+            handlers: Vec::new(),
+            thrown: None,
+            memory: HashMap::new(),
+            native_handlers: HashMap::new(),
+            observers: Vec::new(),
+            fuel: None,
+            instruction_cost: 1,
+            native_costs: HashMap::new(),
+            limits: VmLimits::default(),
+            heap: Heap::default(),
+            coroutines: Vec::new(),
+            free_coroutines: Vec::new(),
+            coroutine_threshold: COROUTINE_THRESHOLD,
+            active: Vec::new(),
+        }
+    }
+
+    pub fn add_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub fn take_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        std::mem::take(&mut self.observers)
+    }
+
+    pub fn set_limits(&mut self, limits: VmLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &VmLimits {
+        &self.limits
+    }
+
+    fn check_stack_depth(&self) -> Result<(), String> {
+        match self.limits.max_stack_depth {
+            Some(max) if self.data_stack.len() > max => Err(format!(
+                "Data stack overflow: limit of {} values exceeded",
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_value_size(&self, value: &Value) -> Result<(), String> {
+        match self.limits.max_value_bytes {
+            Some(max) if value.size() > max => Err(format!(
+                "Value too large: {} bytes exceeds the limit of {}",
+                value.size(),
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Like `set_memory`, but enforces the globals and value size limits.
+    fn try_set_memory(&mut self, name: &str, value: Value) -> Result<(), String> {
+        self.check_value_size(&value)?;
+        if let Some(max) = self.limits.max_globals
+            && self.memory.len() >= max
+            && !self.memory.contains_key(name)
+        {
+            return Err(format!("Too many globals: limit of {} exceeded", max));
+        }
+        self.set_memory(name, value);
+        Ok(())
+    }
+
+    // `None` means unlimited fuel, which is the default.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_add(amount);
+        }
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_instruction_cost(&mut self, cost: u64) {
+        self.instruction_cost = cost;
+    }
+
+    // Charged on top of the instruction cost whenever CALLNATIVE invokes the named native.
+    pub fn set_native_cost(&mut self, name: &str, cost: u64) {
+        self.native_costs.insert(name.to_string(), cost);
+    }
+
+    fn cost(&self, instr: &Instruction) -> u64 {
+        let native = match instr {
+            Instruction::CallNative(name) => Some(name),
+            Instruction::CallValue => match self.data_stack.last() {
+                Some(Value::Function(Function::Native(name))) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        };
+        match native.and_then(|name| self.native_costs.get(name)) {
+            Some(cost) => self.instruction_cost.saturating_add(*cost),
+            None => self.instruction_cost,
+        }
+    }
+
+    // Observers are moved out while they run so that they can be handed the VM.
+    #[inline]
+    fn notify(&mut self, mut event: impl FnMut(&mut dyn Observer, &VM)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in &mut observers {
+            event(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+
+    // Appends the program to the loaded code and moves execution to its first instruction.
+    pub fn load(&mut self, program: Program) {
+        let offset = self.program.instructions.len();
+
+        for (name, addr) in program.labels {
+            self.program.labels.insert(name, addr + offset);
+        }
+        for (addr, signature) in program.functions {
+            self.program.functions.insert(addr + offset, signature);
         }
+        self.program.instructions.extend(program.instructions);
+
+        self.ptr = offset;
     }
 
     pub fn call_native(&mut self, name: &str) {
-        if let Some(&handler) = self.native_handlers.get(name) {
+        if let Some(handler) = self.native_handlers.get(name).cloned() {
             handler(self);
         } else {
             eprintln!("Error: Undefined native handler '{}'", name);
@@ -126,46 +474,119 @@ impl VM {
     }
 
     pub fn debug(&self) {
-        println!("VM State:");
-        println!("Instruction Pointer: {}", self.ptr);
-        println!("Data Stack: {:?}", self.data_stack);
-        println!("Call Stack: {:?}", self.call_stack);
-        println!("Memory: {:?}", self.memory);
+        print!("{}", self.dump());
+    }
+
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        out.push_str("VM State:\n");
+        match self.program.location(self.ptr) {
+            Some(location) => out.push_str(&format!(
+                "Instruction Pointer: {} ({})\n",
+                self.ptr, location
+            )),
+            None => out.push_str(&format!("Instruction Pointer: {}\n", self.ptr)),
+        }
+        out.push_str(&format!("Data Stack: {:?}\n", self.data_stack));
+        let return_addrs = self
+            .call_stack
+            .iter()
+            .map(|frame| frame.return_addr)
+            .collect::<Vec<_>>();
+        out.push_str(&format!("Call Stack: {:?}\n", return_addrs));
+        out.push_str(&format!("Memory: {:?}\n", self.memory));
+        out
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    pub fn data_stack(&self) -> &[Value] {
+        &self.data_stack
+    }
+
+    pub fn memory(&self) -> &HashMap<String, Value> {
+        &self.memory
     }
 
     pub fn pop(&mut self) -> Value {
-        match self.data_stack.pop() {
-            Some(v) => v,
-            None => {
-                eprintln!("Error: Stack underflow");
+        match self.try_pop() {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Error: {}", e);
                 std::process::exit(1);
             }
         }
     }
 
+    pub fn try_pop(&mut self) -> Result<Value, String> {
+        if self.data_stack.len() <= self.frame_base() {
+            return Err("Stack underflow".to_string());
+        }
+        self.data_stack
+            .pop()
+            .ok_or_else(|| "Stack underflow".to_string())
+    }
+
+    // Checks that the current frame has at least `count` values on the stack, returning the
+    // depth of the whole stack.
+    fn require(&self, count: usize, op: &str) -> Result<usize, String> {
+        let len = self.data_stack.len();
+        if len < self.frame_base() + count {
+            return Err(format!("Stack underflow on {}", op));
+        }
+        Ok(len)
+    }
+
+    // The depth of the data stack the current frame cannot pop below.
+    fn frame_base(&self) -> usize {
+        self.call_stack.last().map_or(0, |frame| frame.base)
+    }
+
     pub fn push(&mut self, value: Value) {
         self.data_stack.push(value);
     }
 
-    pub fn dup(&mut self) {
-        if let Some(top) = self.data_stack.last() {
-            self.data_stack.push(top.clone());
-        } else {
-            eprintln!("Error: Stack underflow on DUP");
-            std::process::exit(1);
+    // Like `push`, but enforces the stack depth and value size limits.
+    pub fn try_push(&mut self, value: Value) -> Result<(), String> {
+        self.check_value_size(&value)?;
+        self.data_stack.push(value);
+        self.check_stack_depth()
+    }
+
+    pub fn dup(&mut self) -> Result<(), String> {
+        if self.data_stack.len() <= self.frame_base() {
+            return Err("Stack underflow on DUP".to_string());
+        }
+        match self.data_stack.last() {
+            Some(top) => {
+                self.data_stack.push(top.clone());
+                self.check_stack_depth()
+            }
+            None => Err("Stack underflow on DUP".to_string()),
         }
     }
 
-    pub fn swap(&mut self) {
-        if self.data_stack.len() < 2 {
-            eprintln!("Error: Stack underflow on SWAP");
-            std::process::exit(1);
+    pub fn swap(&mut self) -> Result<(), String> {
+        if self.data_stack.len() < self.frame_base() + 2 {
+            return Err("Stack underflow on SWAP".to_string());
         }
         let len = self.data_stack.len();
         self.data_stack.swap(len - 1, len - 2);
+        Ok(())
     }
 
     pub fn set_memory(&mut self, name: &str, value: Value) {
+        self.notify(|o, vm| o.on_store(vm, name, &value));
         self.memory.insert(name.to_string(), value);
     }
 
@@ -174,511 +595,2089 @@ impl VM {
     }
 
     pub fn free_memory(&mut self, name: &str) {
+        self.notify(|o, vm| o.on_free(vm, name));
         self.memory.remove(name);
     }
 
-    pub fn register_native_handler(&mut self, name: &str, handler: fn(&mut VM)) {
-        self.native_handlers.insert(name.to_string(), handler);
+    // Raises a catchable error carrying `value`. Natives call this and then return; the error
+    // is raised once the native handler has finished.
+    pub fn throw(&mut self, value: Value) {
+        self.thrown = Some(value);
     }
 
-    pub fn run(&mut self) {
-        while self.ptr < self.program.instructions.len() {
-            let instr = &self.program.instructions[self.ptr].clone();
-            match instr {
-                Instruction::Exit => match self.pop() {
-                    Value::Int(code) => std::process::exit(code as i32),
-                    Value::UInt(code) => std::process::exit(code as i32),
-                    _ => {
-                        eprintln!("Error: EXIT expects an integer return code");
-                        std::process::exit(1);
-                    }
-                },
-                Instruction::ExitImmediate(code) => {
-                    std::process::exit(*code as i32);
-                }
+    // Discards handlers installed by functions that have returned without ENDTRY.
+    fn drop_stale_handlers(&mut self) {
+        let depth = self.call_stack.len();
+        while self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.call_depth > depth)
+        {
+            self.handlers.pop();
+        }
+    }
 
-                Instruction::PushInt(v) => {
-                    self.push(Value::Int(*v));
-                }
-                Instruction::PushUInt(v) => {
-                    self.push(Value::UInt(*v));
-                }
-                Instruction::PushFloat(v) => {
-                    self.push(Value::Float(*v));
-                }
-                Instruction::PushBool(v) => {
-                    self.push(Value::Bool(*v));
-                }
-                Instruction::PushString(s) => {
-                    self.push(Value::String(s.clone()));
-                }
-                Instruction::Pop => {
-                    self.pop();
-                }
-                Instruction::Dup => {
-                    self.dup();
-                }
-                Instruction::Swap => {
-                    self.swap();
-                }
+    pub fn register_native_handler(&mut self, name: &str, handler: impl Fn(&mut VM) + 'static) {
+        self.native_handlers
+            .insert(name.to_string(), Rc::new(handler));
+    }
 
-                Instruction::Add => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match lhs.add(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::AddI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    pub fn native_names(&self) -> impl Iterator<Item = &str> {
+        self.native_handlers.keys().map(|name| name.as_str())
+    }
 
-                    match lhs.add(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::AddU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    fn label_target(&self, label: &str) -> Result<usize, String> {
+        self.program
+            .labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("Undefined label '{}'", label))
+    }
 
-                    match lhs.add(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::AddF(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    pub fn frame(&self, index: usize) -> Frame {
+        let debug_info = self.program.debug_info.as_ref();
+        Frame {
+            function: self.program.label_at(index).map(String::from),
+            index,
+            file: debug_info.map(|d| d.file.clone()),
+            line: debug_info.and_then(|d| d.line(index)),
+        }
+    }
 
-                    match lhs.add(&Value::Float(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::Sub => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match lhs.sub(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::SubI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Reconstructs the active frames from the call stack, innermost first. Frames of the
+    // code that resumed a running coroutine follow the coroutine's own frames.
+    pub fn stack_trace(&self) -> Vec<Frame> {
+        let mut trace = vec![self.frame(self.ptr)];
+        for frame in self.call_stack.iter().rev() {
+            trace.push(self.frame(frame.return_addr.saturating_sub(1)));
+        }
+        for &id in self.active.iter().rev() {
+            let resumer = &self.coroutines[id];
+            trace.push(self.frame(resumer.ptr.saturating_sub(1)));
+            for frame in resumer.call_stack.iter().rev() {
+                trace.push(self.frame(frame.return_addr.saturating_sub(1)));
+            }
+        }
+        trace
+    }
 
-                    match lhs.sub(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::SubU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    fn coroutine(&self, value: Value, op: &str) -> Result<usize, String> {
+        match value {
+            Value::Coroutine(id) if id < self.coroutines.len() => Ok(id),
+            Value::Coroutine(id) => Err(format!("Invalid coroutine {}", id)),
+            _ => Err(format!("{} expects a coroutine", op)),
+        }
+    }
 
-                    match lhs.sub(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::SubF(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Moves a string, array or map onto the heap, returning a reference to it.
+    pub fn alloc(&mut self, value: Value) -> Result<Value, String> {
+        match value {
+            Value::String(_) | Value::Array(_) | Value::Map(_) => {}
+            _ => return Err("Only strings, arrays and maps can be stored on the heap".to_string()),
+        }
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        Ok(Value::Ref(self.heap.alloc(value)))
+    }
 
-                    match lhs.sub(&Value::Float(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::Mul => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match lhs.mul(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::MulI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Returns the value behind a reference, or the value itself if it is not a reference.
+    pub fn resolve<'a>(&'a self, value: &'a Value) -> &'a Value {
+        match value {
+            Value::Ref(id) => self.heap.get(*id).unwrap_or(value),
+            _ => value,
+        }
+    }
 
-                    match lhs.mul(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::MulU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
 
-                    match lhs.mul(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::MulF(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Frees heap objects and coroutines that can no longer be reached from the stacks or
+    // variables, returning the number of heap objects freed. References held by the host outside
+    // of the VM do not keep objects alive.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut roots = Vec::new();
+        // Running coroutines hold the contexts that resumed them, so they are always reachable.
+        let mut found = self.active.clone();
+        context_roots(
+            &self.call_stack,
+            self.closure,
+            &self.handlers,
+            &self.data_stack,
+            &mut roots,
+            &mut found,
+        );
+        for value in self.memory.values().chain(&self.thrown) {
+            refs(value, &mut roots);
+            coroutine_refs(value, &mut found);
+        }
 
-                    match lhs.mul(&Value::Float(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
+        // Suspended coroutines are only reachable through coroutine values, which can also be
+        // held by heap objects, so mark until no more coroutines are found.
+        let mut reachable = vec![false; self.coroutines.len()];
+        let marked = loop {
+            while let Some(id) = found.pop() {
+                if id >= reachable.len() || reachable[id] {
+                    continue;
                 }
-                Instruction::Div => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match lhs.div(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
+                reachable[id] = true;
+                let coroutine = &self.coroutines[id];
+                context_roots(
+                    &coroutine.call_stack,
+                    coroutine.closure,
+                    &coroutine.handlers,
+                    &coroutine.data_stack,
+                    &mut roots,
+                    &mut found,
+                );
+            }
+            let marked = self.heap.mark(roots.clone());
+            for (id, object) in self.heap.slots().iter().enumerate() {
+                if marked[id]
+                    && let Some(value) = object
+                {
+                    coroutine_refs(value, &mut found);
                 }
-                Instruction::DivI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+            }
+            found.retain(|&id| id < reachable.len() && !reachable[id]);
+            if found.is_empty() {
+                break marked;
+            }
+        };
 
-                    match lhs.div(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::DivU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+        self.free_coroutines.clear();
+        for (id, coroutine) in self.coroutines.iter_mut().enumerate() {
+            if !reachable[id] {
+                *coroutine = Coroutine::new(CoroutineState::Done, 0);
+                self.free_coroutines.push(id);
+            }
+        }
+        let live = self.coroutines.len() - self.free_coroutines.len();
+        self.coroutine_threshold = COROUTINE_THRESHOLD.max(live * 2);
+        self.heap.sweep(&marked)
+    }
 
-                    match lhs.div(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::DivF(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Pops the operands of an ordering comparison. NaN compares as unordered.
+    fn pop_ordering(&mut self, op: &str) -> Result<Option<Ordering>, String> {
+        let rhs = self.try_pop()?;
+        let lhs = self.try_pop()?;
 
-                    match lhs.div(&Value::Float(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::Mod => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match lhs.mod_op(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::ModI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+        match (lhs, rhs) {
+            (Value::Int(a), Value::Int(b)) => Ok(Some(a.cmp(&b))),
+            (Value::UInt(a), Value::UInt(b)) => Ok(Some(a.cmp(&b))),
+            (Value::Float(a), Value::Float(b)) => Ok(a.partial_cmp(&b)),
+            _ => Err(format!("Type mismatch in {}", op)),
+        }
+    }
 
-                    match lhs.mod_op(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::ModU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    fn pop_zero(&mut self, op: &str) -> Result<bool, String> {
+        match self.try_pop()? {
+            Value::Int(v) => Ok(v == 0),
+            Value::UInt(v) => Ok(v == 0),
+            Value::Float(v) => Ok(v == 0.0),
+            _ => Err(format!("{} expects a number", op)),
+        }
+    }
 
-                    match lhs.mod_op(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::Exp => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
+    // Jumps to `label` if `condition` holds, returning whether it did.
+    fn jump_if(&mut self, label: &str, condition: bool) -> Result<bool, String> {
+        if condition {
+            self.ptr = self.label_target(label)?;
+        }
+        Ok(condition)
+    }
 
-                    match lhs.exp(&rhs) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::ExpI(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // The captured values of the closure currently executing.
+    fn upvalues(&mut self, op: &str) -> Result<&mut Vec<Value>, String> {
+        let env = self
+            .closure
+            .ok_or_else(|| format!("{} used outside of a closure", op))?;
+        match self.heap.get_mut(env) {
+            Some(Value::Array(upvalues)) => Ok(upvalues),
+            _ => Err(format!("Invalid closure environment {}", env)),
+        }
+    }
 
-                    match lhs.exp(&Value::Int(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::ExpU(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    fn heap_object(&self, id: usize) -> Result<&Value, String> {
+        self.heap
+            .get(id)
+            .ok_or_else(|| format!("Invalid reference {}", id))
+    }
 
-                    match lhs.exp(&Value::UInt(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::ExpF(v) => {
-                    let lhs = self.pop();
-                    let rhs = v.clone();
+    // Pops a map, or a reference to one, for reading.
+    fn pop_map(&mut self, op: &str) -> Result<Cow<'_, BTreeMap<MapKey, Value>>, String> {
+        match self.try_pop()? {
+            Value::Map(map) => Ok(Cow::Owned(map)),
+            Value::Ref(id) => match self.heap_object(id)? {
+                Value::Map(map) => Ok(Cow::Borrowed(map)),
+                _ => Err(format!("{} expects a map", op)),
+            },
+            _ => Err(format!("{} expects a map", op)),
+        }
+    }
 
-                    match lhs.exp(&Value::Float(rhs)) {
-                        Ok(result) => self.push(result),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
+    // Pops a map, or a reference to one, and pushes it back after applying `update`. Maps
+    // behind a reference are updated in place, so every holder of the reference sees the change.
+    fn update_map(
+        &mut self,
+        op: &str,
+        update: impl FnOnce(&mut BTreeMap<MapKey, Value>),
+    ) -> Result<(), String> {
+        match self.try_pop()? {
+            Value::Map(mut map) => {
+                update(&mut map);
+                self.try_push(Value::Map(map))
+            }
+            Value::Ref(id) => {
+                match self.heap.get_mut(id) {
+                    Some(Value::Map(map)) => update(map),
+                    _ => return Err(format!("{} expects a map", op)),
                 }
+                self.check_value_size(self.heap_object(id)?)?;
+                self.try_push(Value::Ref(id))
+            }
+            _ => Err(format!("{} expects a map", op)),
+        }
+    }
 
-                Instruction::Load => {
-                    let loc = self.pop();
-                    match loc {
-                        Value::String(v) => match self.get_memory(&v) {
-                            Some(val) => self.push(val.clone()),
-                            None => {
-                                eprintln!("Error: Undefined variable '{}'", v);
-                                std::process::exit(1);
-                            }
-                        },
-                        _ => {
-                            eprintln!("Error: LOAD expects a string as variable name");
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Instruction::LoadImm(name) => match self.get_memory(name) {
-                    Some(val) => self.push(val.clone()),
-                    None => {
-                        eprintln!("Error: Undefined variable '{}'", name);
-                        std::process::exit(1);
-                    }
-                },
-                Instruction::Store => {
-                    let val = self.pop();
-                    let loc = self.pop();
-                    match loc {
-                        Value::String(v) => {
-                            self.set_memory(&v, val);
-                        }
-                        _ => {
-                            eprintln!("Error: STORE expects a string as variable name");
-                            std::process::exit(1);
-                        }
-                    }
+    fn switch_context(&mut self, id: usize) {
+        let coroutine = &mut self.coroutines[id];
+        std::mem::swap(&mut self.ptr, &mut coroutine.ptr);
+        std::mem::swap(&mut self.call_stack, &mut coroutine.call_stack);
+        std::mem::swap(&mut self.closure, &mut coroutine.closure);
+        std::mem::swap(&mut self.data_stack, &mut coroutine.data_stack);
+        std::mem::swap(&mut self.handlers, &mut coroutine.handlers);
+    }
+
+    // Hands control from the running coroutine back to its resumer, passing it a value.
+    fn leave_coroutine(
+        &mut self,
+        id: usize,
+        state: CoroutineState,
+        value: Value,
+    ) -> Result<(), String> {
+        self.exit_coroutine(id, state);
+        self.try_push(value)
+    }
+
+    // Switches from the running coroutine back to the code that resumed it.
+    fn exit_coroutine(&mut self, id: usize, state: CoroutineState) {
+        self.active.pop();
+        self.coroutines[id].state = state;
+        self.switch_context(id);
+        if state == CoroutineState::Done {
+            self.coroutines[id].call_stack = Vec::new();
+            self.coroutines[id].closure = None;
+            self.coroutines[id].data_stack = Vec::new();
+            self.coroutines[id].handlers = Vec::new();
+        }
+    }
+
+    pub fn run(&mut self) -> Result<Outcome, VMError> {
+        loop {
+            if let Some(outcome) = self.step()? {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // Runs at most `n` instructions, returning `Outcome::Paused` if the program is still running.
+    pub fn run_for(&mut self, n: usize) -> Result<Outcome, VMError> {
+        for _ in 0..n {
+            if let Some(outcome) = self.step()? {
+                return Ok(outcome);
+            }
+        }
+        Ok(Outcome::Paused)
+    }
+
+    // Continues a program that paused, yielded or ran out of fuel.
+    pub fn resume(&mut self) -> Result<Outcome, VMError> {
+        self.run()
+    }
+
+    pub fn step(&mut self) -> Result<Option<Outcome>, VMError> {
+        let instr = match self.program.instructions.get(self.ptr) {
+            Some(instr) => instr.clone(),
+            None if !self.active.is_empty() => {
+                return Err(VMError {
+                    message: "Coroutine ran past the end of the program".to_string(),
+                    trace: self.stack_trace(),
+                });
+            }
+            None => return Ok(Some(Outcome::Finished)),
+        };
+
+        // Running out of fuel leaves the VM untouched, so it can be resumed with more.
+        if let Some(fuel) = self.fuel {
+            let cost = self.cost(&instr);
+            if cost > fuel {
+                return Ok(Some(Outcome::OutOfFuel));
+            }
+            self.fuel = Some(fuel - cost);
+        }
+
+        let index = self.ptr;
+        self.notify(|o, vm| o.before_instruction(vm, index, &instr));
+
+        match self.execute(&instr) {
+            Ok(outcome) => {
+                self.notify(|o, vm| o.after_instruction(vm, index, &instr));
+                Ok(outcome)
+            }
+            Err(message) => {
+                if self.catch(&message) {
+                    self.notify(|o, vm| o.after_instruction(vm, index, &instr));
+                    return Ok(None);
                 }
-                Instruction::StoreImm(name) => {
-                    let val = self.pop();
-                    self.set_memory(name, val);
+                self.thrown = None;
+                let error = VMError {
+                    message,
+                    trace: self.stack_trace(),
+                };
+                self.notify(|o, vm| o.on_error(vm, &error));
+                Err(error)
+            }
+        }
+    }
+
+    fn call(&mut self, target: usize, closure: Option<usize>) -> Result<(), String> {
+        if let Some(max) = self.limits.max_call_depth
+            && self.call_stack.len() >= max
+        {
+            return Err(format!(
+                "Call stack overflow: limit of {} frames exceeded",
+                max
+            ));
+        }
+        // Calls to functions without a declared signature share the caller's frame base.
+        let (base, retc) = match self.callee_frame(target)? {
+            Some((base, signature)) => (base, Some(signature.retc)),
+            None => (self.frame_base(), None),
+        };
+        let from = self.ptr;
+        self.call_stack.push(CallFrame {
+            return_addr: from + 1,
+            closure: std::mem::replace(&mut self.closure, closure),
+            base,
+            retc,
+        });
+        self.ptr = target;
+        self.notify(|o, vm| o.on_call(vm, from, target));
+        Ok(())
+    }
+
+    // Jumps to `target` in place of the current frame, which the callee returns from instead.
+    // Falls back to a regular call, returning to the `RET` after the TAILCALL, while a TRY in
+    // the current function is active, when both are declared with different result counts or
+    // when the current function has values on the stack besides the callee's arguments.
+    fn tail_call(&mut self, target: usize) -> Result<(), String> {
+        let depth = self.call_stack.len();
+        if self.handlers.last().is_some_and(|h| h.call_depth >= depth) {
+            return self.call(target, None);
+        }
+        if let Some((args, signature)) = self.callee_frame(target)?
+            && let Some(frame) = self.call_stack.last_mut()
+        {
+            match frame.retc {
+                // Its RET checks the current function's values too, so they can't be dropped.
+                Some(retc) if retc != signature.retc || frame.base != args => {
+                    return self.call(target, None);
                 }
-                Instruction::Free => {
-                    let loc = self.pop();
-                    match loc {
-                        Value::String(v) => {
-                            self.free_memory(&v);
-                        }
-                        _ => {
-                            eprintln!("Error: FREE expects a string as variable name");
-                            std::process::exit(1);
-                        }
-                    }
+                Some(_) => {}
+                None => frame.base = args,
+            }
+            frame.retc = Some(signature.retc);
+        }
+        let from = self.ptr;
+        self.closure = None;
+        self.ptr = target;
+        self.notify(|o, vm| o.on_call(vm, from, target));
+        Ok(())
+    }
+
+    // Checks the arguments of a function declared with `FUNC`, returning its frame base.
+    fn callee_frame(&self, target: usize) -> Result<Option<(usize, Signature)>, String> {
+        let Some(&signature) = self.program.functions.get(&target) else {
+            return Ok(None);
+        };
+        let argc = signature.argc as usize;
+        let available = self.data_stack.len().saturating_sub(self.frame_base());
+        if available < argc {
+            return Err(format!(
+                "Function '{}' expects {} arguments, but only {} are on the stack",
+                self.program.label_at(target).unwrap_or("?"),
+                argc,
+                available
+            ));
+        }
+        Ok(Some((self.data_stack.len() - argc, signature)))
+    }
+
+    fn invoke_native(&mut self, name: &str) -> Result<(), String> {
+        let Some(handler) = self.native_handlers.get(name).cloned() else {
+            return Err(format!("Undefined native handler '{}'", name));
+        };
+        self.notify(|o, vm| o.on_native(vm, name));
+        handler(self);
+        if let Some(value) = &self.thrown {
+            return Err(format!("Uncaught exception: {:?}", value));
+        }
+        // Natives push with `push`, so check what they left behind.
+        self.check_stack_depth()?;
+        if let Some(top) = self.data_stack.last() {
+            self.check_value_size(top)?;
+        }
+        Ok(())
+    }
+
+    // Unwinds to the innermost live handler, passing it the thrown value or the error message.
+    fn catch(&mut self, message: &str) -> bool {
+        // An exception a coroutine does not handle finishes it and is rethrown by the RESUME
+        // that resumed it, so unwind coroutines until a handler is found.
+        let handled = !self.handlers.is_empty()
+            || self
+                .active
+                .iter()
+                .any(|&id| !self.coroutines[id].handlers.is_empty());
+        if !handled {
+            return false;
+        }
+        while self.handlers.is_empty() {
+            let Some(&id) = self.active.last() else {
+                return false;
+            };
+            self.exit_coroutine(id, CoroutineState::Done);
+        }
+        let Some(handler) = self.handlers.pop() else {
+            return false;
+        };
+        let value = self
+            .thrown
+            .take()
+            .unwrap_or_else(|| Value::String(message.to_string()));
+        self.call_stack.truncate(handler.call_depth);
+        self.closure = handler.closure;
+        self.data_stack.truncate(handler.stack_depth);
+        self.data_stack.push(value);
+        self.ptr = handler.target;
+        true
+    }
+
+    fn execute(&mut self, instr: &Instruction) -> Result<Option<Outcome>, String> {
+        match instr {
+            Instruction::Exit => match self.try_pop()? {
+                Value::Int(code) => return Ok(Some(Outcome::Exited(code as i32))),
+                Value::UInt(code) => return Ok(Some(Outcome::Exited(code as i32))),
+                _ => return Err("EXIT expects an integer return code".to_string()),
+            },
+            Instruction::ExitImmediate(code) => {
+                return Ok(Some(Outcome::Exited(*code as i32)));
+            }
+
+            Instruction::PushInt(v) => {
+                self.try_push(Value::Int(*v))?;
+            }
+            Instruction::PushUInt(v) => {
+                self.try_push(Value::UInt(*v))?;
+            }
+            Instruction::PushFloat(v) => {
+                self.try_push(Value::Float(*v))?;
+            }
+            Instruction::PushBool(v) => {
+                self.try_push(Value::Bool(*v))?;
+            }
+            Instruction::PushString(s) => {
+                self.try_push(Value::String(s.clone()))?;
+            }
+            Instruction::PushNil => {
+                self.try_push(Value::Nil)?;
+            }
+            Instruction::Pop => {
+                self.try_pop()?;
+            }
+            Instruction::Dup => {
+                self.dup()?;
+            }
+            Instruction::Swap => {
+                self.swap()?;
+            }
+            Instruction::Over => {
+                let len = self.require(2, "OVER")?;
+                let value = self.data_stack[len - 2].clone();
+                self.try_push(value)?;
+            }
+            Instruction::Rot => {
+                let len = self.require(3, "ROT")?;
+                let value = self.data_stack.remove(len - 3);
+                self.data_stack.push(value);
+            }
+            Instruction::Pick(n) => {
+                let n = *n as usize;
+                let len = self.require(n + 1, "PICK")?;
+                let value = self.data_stack[len - 1 - n].clone();
+                self.try_push(value)?;
+            }
+            Instruction::Roll(n) => {
+                let n = *n as usize;
+                let len = self.require(n + 1, "ROLL")?;
+                let value = self.data_stack.remove(len - 1 - n);
+                self.data_stack.push(value);
+            }
+            Instruction::Drop(n) => {
+                let n = *n as usize;
+                let len = self.require(n, "DROP")?;
+                self.data_stack.truncate(len - n);
+            }
+            Instruction::Dup2 => {
+                let len = self.require(2, "DUP2")?;
+                let next = self.data_stack[len - 2].clone();
+                let top = self.data_stack[len - 1].clone();
+                self.try_push(next)?;
+                self.try_push(top)?;
+            }
+            Instruction::Depth => {
+                let depth = self.data_stack.len() - self.frame_base();
+                self.try_push(Value::UInt(depth as u64))?;
+            }
+
+            Instruction::Add => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.add(&rhs)?)?;
+            }
+            Instruction::AddI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.add(&Value::Int(*v))?)?;
+            }
+            Instruction::AddU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.add(&Value::UInt(*v))?)?;
+            }
+            Instruction::AddF(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.add(&Value::Float(*v))?)?;
+            }
+            Instruction::Sub => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.sub(&rhs)?)?;
+            }
+            Instruction::SubI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.sub(&Value::Int(*v))?)?;
+            }
+            Instruction::SubU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.sub(&Value::UInt(*v))?)?;
+            }
+            Instruction::SubF(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.sub(&Value::Float(*v))?)?;
+            }
+            Instruction::Mul => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mul(&rhs)?)?;
+            }
+            Instruction::MulI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mul(&Value::Int(*v))?)?;
+            }
+            Instruction::MulU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mul(&Value::UInt(*v))?)?;
+            }
+            Instruction::MulF(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mul(&Value::Float(*v))?)?;
+            }
+            Instruction::Div => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.div(&rhs)?)?;
+            }
+            Instruction::DivI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.div(&Value::Int(*v))?)?;
+            }
+            Instruction::DivU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.div(&Value::UInt(*v))?)?;
+            }
+            Instruction::DivF(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.div(&Value::Float(*v))?)?;
+            }
+            Instruction::Mod => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mod_op(&rhs)?)?;
+            }
+            Instruction::ModI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mod_op(&Value::Int(*v))?)?;
+            }
+            Instruction::ModU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.mod_op(&Value::UInt(*v))?)?;
+            }
+            Instruction::Exp => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.exp(&rhs)?)?;
+            }
+            Instruction::ExpI(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.exp(&Value::Int(*v))?)?;
+            }
+            Instruction::ExpU(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.exp(&Value::UInt(*v))?)?;
+            }
+            Instruction::ExpF(v) => {
+                let lhs = self.try_pop()?;
+                self.try_push(lhs.exp(&Value::Float(*v))?)?;
+            }
+
+            Instruction::Load => match self.try_pop()? {
+                Value::String(v) => match self.get_memory(&v) {
+                    Some(val) => self.try_push(val.clone())?,
+                    None => return Err(format!("Undefined variable '{}'", v)),
+                },
+                _ => return Err("LOAD expects a string as variable name".to_string()),
+            },
+            Instruction::LoadImm(name) => match self.get_memory(name) {
+                Some(val) => self.try_push(val.clone())?,
+                None => return Err(format!("Undefined variable '{}'", name)),
+            },
+            Instruction::Store => {
+                let val = self.try_pop()?;
+                match self.try_pop()? {
+                    Value::String(v) => self.try_set_memory(&v, val)?,
+                    _ => return Err("STORE expects a string as variable name".to_string()),
                 }
-                Instruction::FreeImm(name) => {
-                    self.free_memory(name);
+            }
+            Instruction::StoreImm(name) => {
+                let val = self.try_pop()?;
+                self.try_set_memory(name, val)?;
+            }
+            Instruction::Free => match self.try_pop()? {
+                Value::String(v) => self.free_memory(&v),
+                _ => return Err("FREE expects a string as variable name".to_string()),
+            },
+            Instruction::FreeImm(name) => {
+                self.free_memory(name);
+            }
+            Instruction::LoadNil => match self.try_pop()? {
+                Value::String(v) => {
+                    let val = self.get_memory(&v).cloned().unwrap_or(Value::Nil);
+                    self.try_push(val)?;
                 }
+                _ => return Err("LOADN expects a string as variable name".to_string()),
+            },
+            Instruction::LoadNilImm(name) => {
+                let val = self.get_memory(name).cloned().unwrap_or(Value::Nil);
+                self.try_push(val)?;
+            }
+
+            Instruction::CmpEqual => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(Value::Bool(lhs == rhs))?;
+            }
+            Instruction::CmpNotEqual => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                self.try_push(Value::Bool(lhs != rhs))?;
+            }
+            Instruction::CmpGreaterThan => {
+                let ordering = self.pop_ordering("CMP_GREATER_THAN")?;
+                self.try_push(Value::Bool(ordering == Some(Ordering::Greater)))?;
+            }
+            Instruction::CmpLessThan => {
+                let ordering = self.pop_ordering("CMP_LESS_THAN")?;
+                self.try_push(Value::Bool(ordering == Some(Ordering::Less)))?;
+            }
+            Instruction::CmpGreaterEqual => {
+                let ordering = self.pop_ordering("CMP_GREATER_EQUAL")?;
+                self.try_push(Value::Bool(matches!(
+                    ordering,
+                    Some(Ordering::Greater | Ordering::Equal)
+                )))?;
+            }
+            Instruction::CmpLessEqual => {
+                let ordering = self.pop_ordering("CMP_LESS_EQUAL")?;
+                self.try_push(Value::Bool(matches!(
+                    ordering,
+                    Some(Ordering::Less | Ordering::Equal)
+                )))?;
+            }
 
-                Instruction::CmpEqual => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    self.push(Value::Bool(lhs == rhs));
+            Instruction::Jump(label) => {
+                self.ptr = self.label_target(label)?;
+                return Ok(None);
+            }
+            Instruction::JumpIf(label) => match self.try_pop()? {
+                Value::Bool(true) => {
+                    self.ptr = self.label_target(label)?;
+                    return Ok(None);
                 }
-                Instruction::CmpNotEqual => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    self.push(Value::Bool(lhs != rhs));
+                Value::Bool(false) => {
+                    // Do nothing, just continue
                 }
-                Instruction::CmpGreaterThan => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-
-                    match (lhs, rhs) {
-                        (Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a > b)),
-                        (Value::UInt(a), Value::UInt(b)) => self.push(Value::Bool(a > b)),
-                        (Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a > b)),
-                        _ => {
-                            eprintln!("Error: Type mismatch in CMP_GREATER_THAN");
-                            std::process::exit(1);
-                        }
-                    }
+                _ => return Err("JMPIF expects a boolean condition".to_string()),
+            },
+            Instruction::Call(label) => {
+                let target = self.label_target(label)?;
+                self.call(target, None)?;
+                return Ok(None);
+            }
+            Instruction::TailCall(label) => {
+                let target = self.label_target(label)?;
+                self.tail_call(target)?;
+                return Ok(None);
+            }
+            Instruction::CallNative(name) => {
+                self.invoke_native(name)?;
+            }
+            Instruction::PushFunction(label) => {
+                let target = self.label_target(label)?;
+                self.try_push(Value::Function(Function::Address(target)))?;
+            }
+            Instruction::PushNative(name) => {
+                if !self.native_handlers.contains_key(name) {
+                    return Err(format!("Undefined native handler '{}'", name));
                 }
-                Instruction::CmpLessThan => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-
-                    match (lhs, rhs) {
-                        (Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a < b)),
-                        (Value::UInt(a), Value::UInt(b)) => self.push(Value::Bool(a < b)),
-                        (Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a < b)),
-                        _ => {
-                            eprintln!("Error: Type mismatch in CMP_LESS_THAN");
-                            std::process::exit(1);
-                        }
-                    }
+                self.try_push(Value::Function(Function::Native(name.clone())))?;
+            }
+            Instruction::CallValue => match self.try_pop()? {
+                Value::Function(Function::Address(target)) => {
+                    self.call(target, None)?;
+                    return Ok(None);
                 }
-                Instruction::CmpGreaterEqual => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-
-                    match (lhs, rhs) {
-                        (Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a >= b)),
-                        (Value::UInt(a), Value::UInt(b)) => self.push(Value::Bool(a >= b)),
-                        (Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a >= b)),
-                        _ => {
-                            eprintln!("Error: Type mismatch in CMP_GREATER_EQUAL");
-                            std::process::exit(1);
-                        }
+                Value::Function(Function::Closure { address, env }) => {
+                    self.call(address, Some(env))?;
+                    return Ok(None);
+                }
+                Value::Function(Function::Native(name)) => self.invoke_native(&name)?,
+                _ => return Err("CALLV expects a function".to_string()),
+            },
+            Instruction::Ret => {
+                if let Some(CallFrame {
+                    base,
+                    retc: Some(retc),
+                    ..
+                }) = self.call_stack.last()
+                {
+                    let results = self.data_stack.len().saturating_sub(*base);
+                    if results != *retc as usize {
+                        return Err(format!(
+                            "RET expected {} results on the stack, found {}",
+                            retc, results
+                        ));
                     }
                 }
-                Instruction::CmpLessEqual => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-
-                    match (lhs, rhs) {
-                        (Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a <= b)),
-                        (Value::UInt(a), Value::UInt(b)) => self.push(Value::Bool(a <= b)),
-                        (Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a <= b)),
-                        _ => {
-                            eprintln!("Error: Type mismatch in CMP_LESS_EQUAL");
-                            std::process::exit(1);
+                match self.call_stack.pop() {
+                    Some(frame) => {
+                        let from = self.ptr;
+                        let return_addr = frame.return_addr;
+                        self.ptr = return_addr;
+                        self.closure = frame.closure;
+                        self.drop_stale_handlers();
+                        self.notify(|o, vm| o.on_return(vm, from, return_addr));
+                        return Ok(None);
+                    }
+                    // Returning from the body of a coroutine finishes it.
+                    None => match self.active.last() {
+                        Some(&id) => {
+                            let value = self.try_pop().unwrap_or(Value::Nil);
+                            self.leave_coroutine(id, CoroutineState::Done, value)?;
+                            return Ok(None);
                         }
+                        None => return Err("Call stack underflow on RET".to_string()),
+                    },
+                }
+            }
+            Instruction::Yield => {
+                let value = self.try_pop()?;
+                self.ptr += 1;
+                match self.active.last() {
+                    Some(&id) => self.leave_coroutine(id, CoroutineState::Suspended, value)?,
+                    None => return Ok(Some(Outcome::Yielded(value))),
+                }
+                return Ok(None);
+            }
+            Instruction::Spawn(label) => {
+                let target = self.label_target(label)?;
+                if self.free_coroutines.is_empty()
+                    && self.coroutines.len() >= self.coroutine_threshold
+                {
+                    self.collect_garbage();
+                }
+                let coroutine = Coroutine::new(CoroutineState::Suspended, target);
+                let id = match self.free_coroutines.pop() {
+                    Some(id) => {
+                        self.coroutines[id] = coroutine;
+                        id
                     }
+                    None => {
+                        self.coroutines.push(coroutine);
+                        self.coroutines.len() - 1
+                    }
+                };
+                self.try_push(Value::Coroutine(id))?;
+            }
+            Instruction::Resume => {
+                let value = self.try_pop()?;
+                let id = self.coroutine(value, "RESUME")?;
+                match self.coroutines[id].state {
+                    CoroutineState::Suspended => {}
+                    CoroutineState::Running => {
+                        return Err("Cannot resume a running coroutine".to_string());
+                    }
+                    CoroutineState::Done => {
+                        return Err("Cannot resume a finished coroutine".to_string());
+                    }
+                }
+                self.ptr += 1;
+                self.coroutines[id].state = CoroutineState::Running;
+                self.switch_context(id);
+                self.active.push(id);
+                return Ok(None);
+            }
+            Instruction::Try(label) => {
+                let target = self.label_target(label)?;
+                self.handlers.push(Handler {
+                    target,
+                    call_depth: self.call_stack.len(),
+                    stack_depth: self.data_stack.len(),
+                    closure: self.closure,
+                });
+            }
+            Instruction::EndTry => {
+                // Only a handler installed by the current function can be removed by it.
+                let depth = self.call_stack.len();
+                if self.handlers.last().is_none_or(|h| h.call_depth != depth) {
+                    return Err("ENDTRY without a matching TRY".to_string());
                 }
+                self.handlers.pop();
+            }
+            Instruction::Throw => {
+                let value = self.try_pop()?;
+                let message = format!("Uncaught exception: {:?}", value);
+                self.thrown = Some(value);
+                return Err(message);
+            }
+            Instruction::MapNew => {
+                self.try_push(Value::Map(BTreeMap::new()))?;
+            }
+            Instruction::MapGet => {
+                let key = MapKey::from_value(self.try_pop()?)?;
+                match self.pop_map("MAPGET")?.get(&key).cloned() {
+                    Some(value) => self.try_push(value)?,
+                    None => return Err(format!("Key {:?} not found in map", key.to_value())),
+                }
+            }
+            Instruction::MapSet => {
+                let value = self.try_pop()?;
+                let key = MapKey::from_value(self.try_pop()?)?;
+                self.update_map("MAPSET", |map| {
+                    map.insert(key, value);
+                })?;
+            }
+            Instruction::MapHas => {
+                let key = MapKey::from_value(self.try_pop()?)?;
+                let has = self.pop_map("MAPHAS")?.contains_key(&key);
+                self.try_push(Value::Bool(has))?;
+            }
+            Instruction::MapDelete => {
+                let key = MapKey::from_value(self.try_pop()?)?;
+                self.update_map("MAPDEL", |map| {
+                    map.remove(&key);
+                })?;
+            }
+            Instruction::MapKeys => {
+                let keys = self
+                    .pop_map("MAPKEYS")?
+                    .keys()
+                    .map(MapKey::to_value)
+                    .collect();
+                self.try_push(Value::Array(keys))?;
+            }
+            Instruction::MapLen => {
+                let len = self.pop_map("MAPLEN")?.len();
+                self.try_push(Value::UInt(len as u64))?;
+            }
 
-                Instruction::Jump(label) => {
-                    if let Some(&target) = self.program.labels.get(label) {
-                        self.ptr = target;
-                        continue;
-                    } else {
-                        eprintln!("Error: Undefined label '{}'", label);
-                        std::process::exit(1);
-                    }
+            Instruction::MakeClosure(count) => {
+                let address = match self.try_pop()? {
+                    Value::Function(Function::Address(address)) => address,
+                    _ => return Err("MAKECLOSURE expects a function".to_string()),
+                };
+                let count = *count as usize;
+                if self.data_stack.len() < self.frame_base() + count {
+                    return Err("Stack underflow on MAKECLOSURE".to_string());
                 }
-                Instruction::JumpIf(label) => {
-                    let cond = self.pop();
-                    match cond {
-                        Value::Bool(true) => {
-                            if let Some(&target) = self.program.labels.get(label) {
-                                self.ptr = target;
-                                continue;
-                            } else {
-                                eprintln!("Error: Undefined label '{}'", label);
-                                std::process::exit(1);
-                            }
-                        }
-                        Value::Bool(false) => {
-                            // Do nothing, just continue
-                        }
-                        _ => {
-                            eprintln!("Error: JMPIF expects a boolean condition");
-                            std::process::exit(1);
-                        }
-                    }
+                // The captured values stay on the stack until the environment is allocated, so
+                // a collection triggered by the allocation still sees them as roots.
+                let base = self.data_stack.len() - count;
+                let captured = self.data_stack[base..].to_vec();
+                let env = match self.alloc(Value::Array(captured))? {
+                    Value::Ref(env) => env,
+                    _ => unreachable!(),
+                };
+                self.data_stack.truncate(base);
+                self.try_push(Value::Function(Function::Closure { address, env }))?;
+            }
+            Instruction::GetUpvalue(index) => {
+                let value = self.upvalues("GETUPVAL")?.get(*index as usize).cloned();
+                match value {
+                    Some(value) => self.try_push(value)?,
+                    None => return Err(format!("Upvalue {} is out of range", index)),
                 }
-                Instruction::Call(label) => {
-                    if let Some(&target) = self.program.labels.get(label) {
-                        self.call_stack.push(self.ptr + 1);
-                        self.ptr = target;
-                        continue;
-                    } else {
-                        eprintln!("Error: Undefined label '{}'", label);
-                        std::process::exit(1);
-                    }
+            }
+            Instruction::SetUpvalue(index) => {
+                let value = self.try_pop()?;
+                match self.upvalues("SETUPVAL")?.get_mut(*index as usize) {
+                    Some(upvalue) => *upvalue = value,
+                    None => return Err(format!("Upvalue {} is out of range", index)),
+                }
+            }
+
+            Instruction::JumpTable(default, labels) => {
+                let index = match self.try_pop()? {
+                    Value::Int(index) => usize::try_from(index).ok(),
+                    Value::UInt(index) => usize::try_from(index).ok(),
+                    _ => return Err("JMPTABLE expects an integer".to_string()),
+                };
+                let label = index.and_then(|index| labels.get(index)).unwrap_or(default);
+                self.ptr = self.label_target(label)?;
+                return Ok(None);
+            }
+            Instruction::JumpValue => match self.try_pop()? {
+                Value::Function(Function::Address(target)) => {
+                    self.ptr = target;
+                    return Ok(None);
                 }
-                Instruction::CallNative(name) => {
-                    if let Some(&handler) = self.native_handlers.get(name) {
-                        handler(self);
-                    } else {
-                        eprintln!("Error: Undefined native handler '{}'", name);
-                        std::process::exit(1);
+                _ => return Err("JMPV expects a label address pushed by PUSHFN".to_string()),
+            },
+            Instruction::JumpIfNot(label) => match self.try_pop()? {
+                Value::Bool(condition) => {
+                    if self.jump_if(label, !condition)? {
+                        return Ok(None);
                     }
-                    self.ptr += 1;
-                    continue;
                 }
-                Instruction::Ret => {
-                    if let Some(return_addr) = self.call_stack.pop() {
-                        self.ptr = return_addr;
-                        continue;
-                    } else {
-                        eprintln!("Error: Call stack underflow on RET");
-                        std::process::exit(1);
+                _ => return Err("JMPIFNOT expects a boolean condition".to_string()),
+            },
+            Instruction::JumpEqual(label) => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                if self.jump_if(label, lhs == rhs)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpNotEqual(label) => {
+                let rhs = self.try_pop()?;
+                let lhs = self.try_pop()?;
+                if self.jump_if(label, lhs != rhs)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpGreaterThan(label) => {
+                let ordering = self.pop_ordering("JGT")?;
+                if self.jump_if(label, ordering == Some(Ordering::Greater))? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpLessThan(label) => {
+                let ordering = self.pop_ordering("JLT")?;
+                if self.jump_if(label, ordering == Some(Ordering::Less))? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpGreaterEqual(label) => {
+                let ordering = self.pop_ordering("JGE")?;
+                let condition = matches!(ordering, Some(Ordering::Greater | Ordering::Equal));
+                if self.jump_if(label, condition)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpLessEqual(label) => {
+                let ordering = self.pop_ordering("JLE")?;
+                let condition = matches!(ordering, Some(Ordering::Less | Ordering::Equal));
+                if self.jump_if(label, condition)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpZero(label) => {
+                let zero = self.pop_zero("JZ")?;
+                if self.jump_if(label, zero)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpNotZero(label) => {
+                let zero = self.pop_zero("JNZ")?;
+                if self.jump_if(label, !zero)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpNil(label) => {
+                let nil = self.try_pop()? == Value::Nil;
+                if self.jump_if(label, nil)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::JumpNotNil(label) => {
+                let nil = self.try_pop()? == Value::Nil;
+                if self.jump_if(label, !nil)? {
+                    return Ok(None);
+                }
+            }
+
+            Instruction::Box => {
+                // The value stays on the stack until it is on the heap, so a collection
+                // triggered by the allocation still sees the objects it refers to as roots.
+                let len = self.require(1, "BOX")?;
+                let value = self.data_stack[len - 1].clone();
+                let reference = self.alloc(value)?;
+                self.try_pop()?;
+                self.try_push(reference)?;
+            }
+            Instruction::Unbox => match self.try_pop()? {
+                Value::Ref(id) => {
+                    let value = self.heap_object(id)?.clone();
+                    self.try_push(value)?;
+                }
+                _ => return Err("UNBOX expects a reference".to_string()),
+            },
+            Instruction::SetRef => {
+                let value = self.try_pop()?;
+                match self.try_pop()? {
+                    Value::Ref(id) => {
+                        self.check_value_size(&value)?;
+                        match self.heap.get_mut(id) {
+                            Some(object) => *object = value,
+                            None => return Err(format!("Invalid reference {}", id)),
+                        }
                     }
+                    _ => return Err("SETREF expects a reference".to_string()),
                 }
             }
 
-            self.ptr += 1;
+            Instruction::Done => {
+                let value = self.try_pop()?;
+                let id = self.coroutine(value, "DONE")?;
+                self.try_push(Value::Bool(
+                    self.coroutines[id].state == CoroutineState::Done,
+                ))?;
+            }
+        }
+
+        self.ptr += 1;
+        Ok(None)
+    }
+}
+
+// Collects the heap handles and coroutines referenced by an execution context.
+fn context_roots(
+    call_stack: &[CallFrame],
+    closure: Option<usize>,
+    handlers: &[Handler],
+    data_stack: &[Value],
+    roots: &mut Vec<usize>,
+    coroutines: &mut Vec<usize>,
+) {
+    roots.extend(closure);
+    roots.extend(call_stack.iter().filter_map(|frame| frame.closure));
+    roots.extend(handlers.iter().filter_map(|handler| handler.closure));
+    for value in data_stack {
+        refs(value, roots);
+        coroutine_refs(value, coroutines);
+    }
+}
+
+// Collects the coroutines referenced by `value`.
+fn coroutine_refs(value: &Value, out: &mut Vec<usize>) {
+    match value {
+        Value::Coroutine(id) => out.push(*id),
+        Value::Array(items) => items.iter().for_each(|item| coroutine_refs(item, out)),
+        Value::Map(entries) => entries.values().for_each(|item| coroutine_refs(item, out)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tail_calls;
+
+    fn run(source: &str) -> VM {
+        let mut vm = VM::new(Program::from_source(source).unwrap());
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn box_keeps_contents_alive_across_collection() {
+        // The map holds the only reference to a boxed string, and boxing the map triggers a
+        // collection once the garbage boxes fill the heap up to its threshold.
+        let vm = run("
+            MAPNEW
+            PUSHS a
+            PUSHS inner
+            BOX
+            MAPSET
+            PUSHI 0
+            STORE_IMM i
+            LABEL loop
+            PUSHS garbage
+            BOX
+            POP
+            LOAD_IMM i
+            ADDI 1
+            DUP
+            STORE_IMM i
+            PUSHI 255
+            CMPLT
+            JMPIF loop
+            BOX
+            UNBOX
+            PUSHS a
+            MAPGET
+            UNBOX
+        ");
+        assert_eq!(vm.gc_stats().collections, 1);
+        assert_eq!(vm.data_stack(), [Value::String("inner".to_string())]);
+    }
+
+    #[test]
+    fn handler_is_dropped_when_its_function_returns() {
+        let mut vm = VM::new(
+            Program::from_source(
+                "
+            CALL f
+            CALL g
+            EXIT_IMM 0
+            LABEL f
+            TRY h
+            RET
+            LABEL h
+            EXIT_IMM 1
+            LABEL g
+            PUSHI 1
+            PUSHI 0
+            DIV
+            RET
+        ",
+            )
+            .unwrap(),
+        );
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.message, "Division by zero");
+    }
+
+    #[test]
+    fn coroutine_exception_reaches_resumer() {
+        let vm = run("
+            SPAWN co
+            TRY h
+            RESUME
+            ENDTRY
+            PUSHS unreachable
+            LABEL h
+            EXIT_IMM 0
+            LABEL co
+            PUSHI 42
+            THROW
+        ");
+        assert_eq!(vm.data_stack(), [Value::Int(42)]);
+    }
+
+    fn tail_called(source: &str) -> VM {
+        VM::new(tail_calls(&Program::from_source(source).unwrap()))
+    }
+
+    #[test]
+    fn tail_call_reuses_frame() {
+        let mut vm = tail_called(
+            "
+            PUSHI 100
+            CALL count
+            EXIT_IMM 0
+            FUNC count 1 1
+            DUP
+            JZ done
+            SUBI 1
+            CALL count
+            RET
+            LABEL done
+            RET
+        ",
+        );
+        vm.set_limits(VmLimits {
+            max_call_depth: Some(2),
+            ..VmLimits::default()
+        });
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(0)]);
+    }
+
+    #[test]
+    fn tail_call_keeps_values_checked_by_ret() {
+        let source = "
+            PUSHI 7
+            CALL f
+            EXIT_IMM 0
+            FUNC f 1 1
+            PUSHI 99
+            PUSHI 1
+            CALL g
+            RET
+            FUNC g 1 1
+            RET
+        ";
+        let expected = VM::new(Program::from_source(source).unwrap())
+            .run()
+            .unwrap_err();
+        let error = tail_called(source).run().unwrap_err();
+        assert_eq!(error.message, expected.message);
+        assert_eq!(
+            error.message,
+            "RET expected 1 results on the stack, found 3"
+        );
+    }
+
+    #[test]
+    fn tail_call_with_different_result_count_returns_normally() {
+        let mut vm = tail_called(
+            "
+            PUSHI 7
+            CALL f
+            EXIT_IMM 0
+            FUNC f 1 2
+            POP
+            PUSHI 5
+            PUSHI 1
+            CALL g
+            RET
+            FUNC g 1 1
+            RET
+        ",
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(5), Value::Int(1)]);
+    }
+
+    #[test]
+    fn endtry_does_not_remove_callers_handler() {
+        let vm = run("
+            TRY h
+            CALL f
+            PUSHI 1
+            PUSHI 0
+            DIV
+            LABEL h
+            EXIT_IMM 0
+            LABEL f
+            ENDTRY
+            RET
+        ");
+        assert_eq!(
+            vm.data_stack(),
+            [Value::String("ENDTRY without a matching TRY".to_string())]
+        );
+    }
+
+    #[test]
+    fn unreachable_coroutines_are_collected() {
+        let mut vm = run("
+            SPAWN co
+            DUP
+            RESUME
+            POP
+            STORE_IMM kept
+            SPAWN co
+            DUP
+            RESUME
+            POP
+            POP
+            EXIT_IMM 0
+            LABEL co
+            PUSHS value
+            BOX
+            PUSHI 0
+            YIELD
+        ");
+        assert_eq!(vm.collect_garbage(), 1);
+        assert_eq!(vm.free_coroutines, [1]);
+    }
+
+    #[test]
+    fn spawn_reuses_collected_coroutine_slots() {
+        let vm = run("
+            PUSHI 0
+            STORE_IMM i
+            LABEL loop
+            SPAWN co
+            POP
+            LOAD_IMM i
+            ADDI 1
+            DUP
+            STORE_IMM i
+            PUSHI 1000
+            CMPLT
+            JMPIF loop
+            EXIT_IMM 0
+            LABEL co
+            PUSHN
+            YIELD
+        ");
+        assert!(vm.coroutines.len() <= COROUTINE_THRESHOLD);
+    }
+
+    #[test]
+    fn load_keeps_state_between_snippets() {
+        let mut vm = VM::new(Program::default());
+        for snippet in [
+            "PUSHI 6\nSTORE_IMM x",
+            "JMP skip\nLABEL double\nPUSHI 2\nMUL\nRET\nLABEL skip",
+            "LOAD_IMM x\nCALL double",
+        ] {
+            vm.load(Program::from_source(snippet).unwrap());
+            assert_eq!(vm.run().unwrap(), Outcome::Finished);
         }
+        assert_eq!(vm.data_stack(), [Value::Int(12)]);
+        assert_eq!(vm.memory.get("x"), Some(&Value::Int(6)));
+    }
+
+    #[test]
+    fn load_after_error_runs_new_snippet() {
+        let mut vm = VM::new(Program::default());
+        vm.load(Program::from_source("PUSHI 1\nPOP\nPOP").unwrap());
+        assert_eq!(vm.run().unwrap_err().message, "Stack underflow");
+        vm.load(Program::from_source("PUSHI 2").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(2)]);
+    }
+
+    #[test]
+    fn error_carries_stack_trace() {
+        let source = "CALL a\nEXIT_IMM 0\nLABEL a\nCALL b\nRET\nLABEL b\nPOP\nRET\n";
+        let program = Program::from_source_with_debug_info(source, "main.vvm").unwrap();
+        let error = VM::new(program).run().unwrap_err();
+        let functions = error.trace.iter().map(|f| f.function.as_deref());
+        assert_eq!(functions.collect::<Vec<_>>(), [Some("b"), Some("a"), None]);
+        assert_eq!(
+            error.to_string(),
+            "Stack underflow\n    at b (instruction 4, main.vvm:7)\n    \
+             at a (instruction 2, main.vvm:4)\n    at <main> (instruction 0, main.vvm:1)"
+        );
+    }
+
+    #[test]
+    fn stack_trace_includes_resumer_of_coroutine() {
+        let error = VM::new(
+            Program::from_source("CALL f\nLABEL f\nSPAWN co\nRESUME\nLABEL co\nPOP").unwrap(),
+        )
+        .run()
+        .unwrap_err();
+        let functions = error.trace.iter().map(|f| f.function.as_deref());
+        assert_eq!(functions.collect::<Vec<_>>(), [Some("co"), Some("f"), None]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<std::cell::RefCell<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn on_call(&mut self, _vm: &VM, from: usize, target: usize) {
+            self.0
+                .borrow_mut()
+                .push(format!("call {} {}", from, target));
+        }
+
+        fn on_return(&mut self, _vm: &VM, from: usize, target: usize) {
+            self.0
+                .borrow_mut()
+                .push(format!("return {} {}", from, target));
+        }
+
+        fn on_native(&mut self, _vm: &VM, name: &str) {
+            self.0.borrow_mut().push(format!("native {}", name));
+        }
+
+        fn on_store(&mut self, _vm: &VM, name: &str, value: &Value) {
+            self.0
+                .borrow_mut()
+                .push(format!("store {} {:?}", name, value));
+        }
+
+        fn on_free(&mut self, _vm: &VM, name: &str) {
+            self.0.borrow_mut().push(format!("free {}", name));
+        }
+
+        fn on_error(&mut self, _vm: &VM, error: &VMError) {
+            self.0.borrow_mut().push(format!("error {}", error.message));
+        }
+    }
+
+    #[test]
+    fn observers_receive_events() {
+        let mut vm = VM::new(
+            Program::from_source(
+                "
+            PUSHI 1
+            STORE_IMM x
+            FREE_IMM x
+            CALL f
+            POP
+            LABEL f
+            CALLNATIVE noop
+            RET
+        ",
+            )
+            .unwrap(),
+        );
+        vm.register_native_handler("noop", |_| {});
+        let recorder = Recorder::default();
+        vm.add_observer(recorder.clone());
+        vm.run().unwrap_err();
+        assert_eq!(
+            *recorder.0.borrow(),
+            [
+                "store x Int(1)",
+                "free x",
+                "call 3 5",
+                "native noop",
+                "return 6 4",
+                "error Stack underflow",
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_fuel_can_be_resumed() {
+        let mut vm = VM::new(Program::from_source("PUSHI 1\nPUSHI 2\nADD").unwrap());
+        vm.set_fuel(Some(2));
+        assert_eq!(vm.run().unwrap(), Outcome::OutOfFuel);
+        assert_eq!(vm.ptr(), 2);
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.resume().unwrap(), Outcome::OutOfFuel);
+
+        vm.add_fuel(1);
+        assert_eq!(vm.resume().unwrap(), Outcome::Finished);
+        assert_eq!(vm.data_stack(), [Value::Int(3)]);
+    }
+
+    #[test]
+    fn native_cost_is_charged_on_top_of_instruction_cost() {
+        let mut vm = VM::new(Program::from_source("CALLNATIVE slow\nCALLNATIVE slow").unwrap());
+        vm.register_native_handler("slow", |_| {});
+        vm.set_instruction_cost(2);
+        vm.set_native_cost("slow", 10);
+        vm.set_fuel(Some(20));
+        assert_eq!(vm.run().unwrap(), Outcome::OutOfFuel);
+        assert_eq!(vm.fuel(), Some(8));
+        assert_eq!(vm.ptr(), 1);
+    }
+
+    fn limit_error(source: &str, limits: VmLimits) -> String {
+        let mut vm = VM::new(Program::from_source(source).unwrap());
+        vm.set_limits(limits);
+        vm.run().unwrap_err().message
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let stack = VmLimits {
+            max_stack_depth: Some(2),
+            ..VmLimits::default()
+        };
+        assert_eq!(
+            limit_error("PUSHI 1\nDUP\nDUP", stack),
+            "Data stack overflow: limit of 2 values exceeded"
+        );
+
+        let calls = VmLimits {
+            max_call_depth: Some(3),
+            ..VmLimits::default()
+        };
+        assert_eq!(
+            limit_error("LABEL f\nCALL f", calls),
+            "Call stack overflow: limit of 3 frames exceeded"
+        );
+
+        let globals = VmLimits {
+            max_globals: Some(1),
+            ..VmLimits::default()
+        };
+        let source = "PUSHI 1\nSTORE_IMM a\nPUSHI 2\nSTORE_IMM a\nPUSHI 3\nSTORE_IMM b";
+        assert_eq!(
+            limit_error(source, globals),
+            "Too many globals: limit of 1 exceeded"
+        );
+
+        let values = VmLimits {
+            max_value_bytes: Some(4),
+            ..VmLimits::default()
+        };
+        assert_eq!(
+            limit_error("PUSHS hello\nSTORE_IMM s", values),
+            "Value too large: 5 bytes exceeds the limit of 4"
+        );
+    }
+
+    #[test]
+    fn run_for_pauses_and_resumes() {
+        let mut vm = VM::new(Program::from_source("PUSHI 1\nPUSHI 2\nADD").unwrap());
+        assert_eq!(vm.run_for(2).unwrap(), Outcome::Paused);
+        assert_eq!(vm.data_stack(), [Value::Int(1), Value::Int(2)]);
+        assert_eq!(vm.resume().unwrap(), Outcome::Finished);
+        assert_eq!(vm.data_stack(), [Value::Int(3)]);
+    }
+
+    #[test]
+    fn yield_returns_to_host() {
+        let mut vm = VM::new(Program::from_source("PUSHI 1\nYIELD\nPUSHI 2\nYIELD").unwrap());
+        assert_eq!(vm.run().unwrap(), Outcome::Yielded(Value::Int(1)));
+        assert_eq!(vm.resume().unwrap(), Outcome::Yielded(Value::Int(2)));
+        assert_eq!(vm.resume().unwrap(), Outcome::Finished);
+        assert!(vm.data_stack().is_empty());
+    }
+
+    #[test]
+    fn coroutine_yields_until_done() {
+        let vm = run("
+            SPAWN gen
+            STORE_IMM g
+            LOAD_IMM g
+            RESUME
+            LOAD_IMM g
+            RESUME
+            LOAD_IMM g
+            DONE
+            LOAD_IMM g
+            RESUME
+            LOAD_IMM g
+            DONE
+            EXIT_IMM 0
+            LABEL gen
+            PUSHI 1
+            YIELD
+            PUSHI 2
+            YIELD
+            PUSHI 3
+            RET
+        ");
+        assert_eq!(
+            vm.data_stack(),
+            [
+                Value::Int(1),
+                Value::Int(2),
+                Value::Bool(false),
+                Value::Int(3),
+                Value::Bool(true)
+            ]
+        );
+    }
+
+    fn error(source: &str) -> String {
+        let mut vm = VM::new(Program::from_source(source).unwrap());
+        vm.run().unwrap_err().message
+    }
+
+    #[test]
+    fn invalid_resumes_are_errors() {
+        assert_eq!(
+            error("SPAWN co\nDUP\nRESUME\nPOP\nRESUME\nEXIT_IMM 0\nLABEL co\nRET"),
+            "Cannot resume a finished coroutine"
+        );
+        assert_eq!(
+            error("SPAWN co\nDUP\nSTORE_IMM c\nRESUME\nEXIT_IMM 0\nLABEL co\nLOAD_IMM c\nRESUME"),
+            "Cannot resume a running coroutine"
+        );
+        assert_eq!(error("PUSHI 1\nRESUME"), "RESUME expects a coroutine");
+        assert_eq!(
+            error("SPAWN co\nRESUME\nEXIT_IMM 0\nLABEL co"),
+            "Coroutine ran past the end of the program"
+        );
+    }
+
+    #[test]
+    fn throw_unwinds_to_handler() {
+        let vm = run("
+            PUSHI 1
+            TRY h
+            PUSHI 2
+            CALL f
+            EXIT_IMM 1
+            LABEL f
+            PUSHI 3
+            PUSHS oops
+            THROW
+            LABEL h
+            EXIT_IMM 0
+        ");
+        assert!(vm.call_stack().is_empty());
+        assert_eq!(
+            vm.data_stack(),
+            [Value::Int(1), Value::String("oops".to_string())]
+        );
+    }
+
+    #[test]
+    fn inner_handler_catches_first() {
+        let vm = run("
+            TRY outer
+            TRY inner
+            PUSHI 1
+            THROW
+            LABEL inner
+            PUSHI 2
+            THROW
+            LABEL outer
+            EXIT_IMM 0
+        ");
+        assert_eq!(vm.data_stack(), [Value::Int(2)]);
+    }
+
+    #[test]
+    fn uncaught_exceptions_are_errors() {
+        assert_eq!(error("PUSHI 7\nTHROW"), "Uncaught exception: Int(7)");
+        assert_eq!(error("ENDTRY"), "ENDTRY without a matching TRY");
+        assert_eq!(
+            error("TRY h\nENDTRY\nPUSHI 7\nTHROW\nLABEL h"),
+            "Uncaught exception: Int(7)"
+        );
+    }
+
+    #[test]
+    fn natives_can_throw() {
+        let mut vm = VM::new(Program::from_source("TRY h\nCALLNATIVE fail\nLABEL h").unwrap());
+        vm.register_native_handler("fail", |vm| vm.throw(Value::Int(5)));
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(5)]);
+    }
+
+    #[test]
+    fn map_instructions() {
+        let vm = run("
+            MAPNEW
+            PUSHS b
+            PUSHI 2
+            MAPSET
+            PUSHS a
+            PUSHI 1
+            MAPSET
+            PUSHS b
+            PUSHI 3
+            MAPSET
+            DUP
+            PUSHS b
+            MAPGET
+            SWAP
+            DUP
+            MAPKEYS
+            SWAP
+            PUSHS a
+            MAPDEL
+            DUP
+            PUSHS a
+            MAPHAS
+            SWAP
+            MAPLEN
+        ");
+        assert_eq!(
+            vm.data_stack(),
+            [
+                Value::Int(3),
+                Value::Array(vec![
+                    Value::String("a".to_string()),
+                    Value::String("b".to_string())
+                ]),
+                Value::Bool(false),
+                Value::UInt(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_map_operations_are_errors() {
+        assert_eq!(
+            error("MAPNEW\nPUSHI 1\nMAPGET"),
+            "Key Int(1) not found in map"
+        );
+        assert_eq!(
+            error("MAPNEW\nPUSHF 1.5\nPUSHI 1\nMAPSET"),
+            "Map keys must be integers, booleans or strings"
+        );
+        assert_eq!(error("PUSHI 1\nMAPLEN"), "MAPLEN expects a map");
+    }
+
+    #[test]
+    fn loadn_pushes_nil_for_undefined_variables() {
+        let vm = run("
+            LOADN_IMM missing
+            PUSHI 1
+            STORE_IMM x
+            PUSHS x
+            LOADN
+            PUSHS missing
+            LOADN
+            PUSHN
+            CMPEQ
+            PUSHN
+            PUSHI 0
+            CMPEQ
+        ");
+        assert_eq!(
+            vm.data_stack(),
+            [
+                Value::Nil,
+                Value::Int(1),
+                Value::Bool(true),
+                Value::Bool(false)
+            ]
+        );
+        assert_eq!(error("LOAD_IMM missing"), "Undefined variable 'missing'");
+    }
+
+    #[test]
+    fn print_shows_nil() {
+        let printed = Rc::new(std::cell::RefCell::new(String::new()));
+        let mut vm = VM::new(Program::from_source("PUSHN\nCALLNATIVE print").unwrap());
+        let out = printed.clone();
+        crate::register_natives(&mut vm, move |s| out.borrow_mut().push_str(s));
+        vm.run().unwrap();
+        assert_eq!(*printed.borrow(), "nil");
+    }
+
+    #[test]
+    fn references_share_their_value() {
+        let vm = run("
+            PUSHS before
+            BOX
+            DUP
+            STORE_IMM r
+            PUSHS after
+            SETREF
+            LOAD_IMM r
+            UNBOX
+        ");
+        assert_eq!(vm.data_stack(), [Value::String("after".to_string())]);
+    }
+
+    #[test]
+    fn collection_frees_unreachable_objects() {
+        let mut vm = run("
+            PUSHS kept
+            BOX
+            STORE_IMM r
+            PUSHS dropped
+            BOX
+            POP
+            PUSHS on_stack
+            BOX
+        ");
+        assert_eq!(vm.gc_stats().live, 3);
+        assert_eq!(vm.collect_garbage(), 1);
+        let stats = vm.gc_stats();
+        assert_eq!((stats.collections, stats.freed, stats.live), (1, 1, 2));
+        let kept = vm.get_memory("r").unwrap().clone();
+        assert_eq!(vm.resolve(&kept), &Value::String("kept".to_string()));
+    }
+
+    #[test]
+    fn invalid_reference_operations_are_errors() {
+        assert_eq!(
+            error("PUSHI 1\nBOX"),
+            "Only strings, arrays and maps can be stored on the heap"
+        );
+        assert_eq!(error("PUSHI 1\nUNBOX"), "UNBOX expects a reference");
+        assert_eq!(
+            error("PUSHI 1\nPUSHI 2\nSETREF"),
+            "SETREF expects a reference"
+        );
+    }
+
+    #[test]
+    fn function_values_can_be_called() {
+        let mut vm = VM::new(
+            Program::from_source(
+                "
+            PUSHI 4
+            PUSHFN double
+            DUP
+            STORE_IMM f
+            CALLV
+            LOAD_IMM f
+            CALLV
+            PUSHNATIVE negate
+            CALLV
+            EXIT_IMM 0
+            LABEL double
+            PUSHI 2
+            MUL
+            RET
+        ",
+            )
+            .unwrap(),
+        );
+        vm.register_native_handler("negate", |vm| {
+            let value = vm.pop();
+            vm.push(Value::Int(0).sub(&value).unwrap());
+        });
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(-16)]);
+    }
+
+    #[test]
+    fn invalid_function_values_are_errors() {
+        assert_eq!(error("PUSHI 1\nCALLV"), "CALLV expects a function");
+        assert_eq!(
+            error("PUSHNATIVE missing"),
+            "Undefined native handler 'missing'"
+        );
+    }
+
+    #[test]
+    fn closure_upvalues_persist_between_calls() {
+        let vm = run("
+            PUSHI 0
+            PUSHFN counter
+            MAKECLOSURE 1
+            DUP
+            STORE_IMM next
+            CALLV
+            LOAD_IMM next
+            CALLV
+            EXIT_IMM 0
+            LABEL counter
+            GETUPVAL 0
+            ADDI 1
+            DUP
+            SETUPVAL 0
+            RET
+        ");
+        assert_eq!(vm.data_stack(), [Value::Int(1), Value::Int(2)]);
+    }
+
+    #[test]
+    fn invalid_closure_operations_are_errors() {
+        assert_eq!(error("GETUPVAL 0"), "GETUPVAL used outside of a closure");
+        assert_eq!(
+            error("PUSHI 1\nMAKECLOSURE 1"),
+            "MAKECLOSURE expects a function"
+        );
+        assert_eq!(
+            error("PUSHFN f\nMAKECLOSURE 1\nLABEL f"),
+            "Stack underflow on MAKECLOSURE"
+        );
+        assert_eq!(
+            error("PUSHFN f\nMAKECLOSURE 0\nCALLV\nLABEL f\nGETUPVAL 0"),
+            "Upvalue 0 is out of range"
+        );
+    }
+
+    #[test]
+    fn func_passes_arguments_and_results() {
+        let vm = run("
+            PUSHI 9
+            PUSHI 3
+            PUSHI 4
+            CALL add
+            EXIT_IMM 0
+            FUNC add 2 1
+            ADD
+            RET
+        ");
+        assert_eq!(vm.data_stack(), [Value::Int(9), Value::Int(7)]);
+    }
+
+    #[test]
+    fn func_signature_errors() {
+        assert_eq!(
+            error("PUSHI 1\nCALL f\nEXIT_IMM 0\nFUNC f 2 1\nRET"),
+            "Function 'f' expects 2 arguments, but only 1 are on the stack"
+        );
+        assert_eq!(
+            error("PUSHI 1\nCALL f\nEXIT_IMM 0\nFUNC f 1 1\nPOP\nRET"),
+            "RET expected 1 results on the stack, found 0"
+        );
+        assert_eq!(
+            error("PUSHI 1\nPUSHI 2\nCALL f\nEXIT_IMM 0\nFUNC f 1 0\nPOP\nPOP\nRET"),
+            "Stack underflow"
+        );
+    }
+
+    #[test]
+    fn loaded_func_is_checked() {
+        let mut vm = VM::new(Program::default());
+        let snippet = "JMP skip\nFUNC f 1 1\nRET\nLABEL skip";
+        vm.load(Program::from_source(snippet).unwrap());
+        vm.run().unwrap();
+        vm.load(Program::from_source("CALL f").unwrap());
+        assert_eq!(
+            vm.run().unwrap_err().message,
+            "Function 'f' expects 1 arguments, but only 0 are on the stack"
+        );
+    }
+
+    #[test]
+    fn tail_call_inside_try_keeps_callers_frame() {
+        let mut vm = tail_called(
+            "
+            CALL f
+            EXIT_IMM 0
+            LABEL f
+            PUSHI 7
+            TRY h
+            PUSHI 1
+            CALL g
+            RET
+            LABEL h
+            ADD
+            RET
+            FUNC g 1 1
+            THROW
+        ",
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.data_stack(), [Value::Int(8)]);
+    }
+
+    #[test]
+    fn jump_table_selects_label_or_default() {
+        let target = |index: &str| {
+            let vm = run(&format!(
+                "{}\nJMPTABLE d a b\nLABEL a\nPUSHS a\nEXIT_IMM 0\nLABEL b\nPUSHS b\nEXIT_IMM 0\n\
+                 LABEL d\nPUSHS d",
+                index
+            ));
+            vm.data_stack()[0].clone()
+        };
+        assert_eq!(target("PUSHI 0"), Value::String("a".to_string()));
+        assert_eq!(target("PUSHU 1"), Value::String("b".to_string()));
+        assert_eq!(target("PUSHI 2"), Value::String("d".to_string()));
+        assert_eq!(target("PUSHI -1"), Value::String("d".to_string()));
+    }
+
+    #[test]
+    fn jmpv_jumps_to_function_value() {
+        let vm = run("PUSHFN end\nJMPV\nPUSHI 1\nLABEL end\nPUSHI 2");
+        assert_eq!(vm.data_stack(), [Value::Int(2)]);
+    }
+
+    #[test]
+    fn invalid_computed_jumps_are_errors() {
+        assert_eq!(
+            error("PUSHF 1.0\nJMPTABLE a\nLABEL a"),
+            "JMPTABLE expects an integer"
+        );
+        assert_eq!(
+            error("PUSHI 1\nJMPV"),
+            "JMPV expects a label address pushed by PUSHFN"
+        );
+        assert_eq!(
+            Program::from_source("JMPTABLE").unwrap_err(),
+            "JMPTABLE expects a default label"
+        );
+    }
+
+    // Whether `branch` jumps after running `setup`, checking that its operands were consumed.
+    fn taken(setup: &str, branch: &str) -> bool {
+        let vm = run(&format!(
+            "{}\n{} yes\nPUSHB false\nEXIT_IMM 0\nLABEL yes\nPUSHB true",
+            setup, branch
+        ));
+        match vm.data_stack() {
+            [Value::Bool(taken)] => *taken,
+            stack => panic!("unexpected stack {:?}", stack),
+        }
+    }
+
+    #[test]
+    fn conditional_branches() {
+        assert!(taken("PUSHB false", "JMPIFNOT"));
+        assert!(!taken("PUSHB true", "JMPIFNOT"));
+        assert!(taken("PUSHS a\nPUSHS a", "JEQ"));
+        assert!(!taken("PUSHI 1\nPUSHU 1", "JEQ"));
+        assert!(taken("PUSHI 1\nPUSHI 2", "JNE"));
+        assert!(taken("PUSHI 2\nPUSHI 1", "JGT"));
+        assert!(!taken("PUSHI 1\nPUSHI 1", "JGT"));
+        assert!(taken("PUSHF 1.0\nPUSHF 2.0", "JLT"));
+        assert!(taken("PUSHU 1\nPUSHU 1", "JGE"));
+        assert!(taken("PUSHI 1\nPUSHI 1", "JLE"));
+        assert!(!taken("PUSHI 2\nPUSHI 1", "JLE"));
+        assert!(taken("PUSHF 0.0", "JZ"));
+        assert!(!taken("PUSHI 3", "JZ"));
+        assert!(taken("PUSHU 3", "JNZ"));
+        assert!(taken("PUSHN", "JNIL"));
+        assert!(!taken("PUSHI 0", "JNIL"));
+        assert!(taken("PUSHI 0", "JNNIL"));
+    }
+
+    #[test]
+    fn nan_comparisons_do_not_branch() {
+        let nan = "PUSHF NaN\nPUSHF 1.0";
+        assert!(!taken(nan, "JLT"));
+        assert!(!taken(nan, "JGE"));
+    }
+
+    #[test]
+    fn invalid_branch_operands_are_errors() {
+        assert_eq!(
+            error("PUSHI 1\nJMPIFNOT a\nLABEL a"),
+            "JMPIFNOT expects a boolean condition"
+        );
+        assert_eq!(error("PUSHS a\nJZ a\nLABEL a"), "JZ expects a number");
+        assert_eq!(
+            error("PUSHI 1\nPUSHU 1\nJGT a\nLABEL a"),
+            "Type mismatch in JGT"
+        );
+    }
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|&v| Value::Int(v)).collect()
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        let stack = |ops: &str| {
+            let vm = run(&format!("PUSHI 1\nPUSHI 2\nPUSHI 3\n{}", ops));
+            vm.data_stack().to_vec()
+        };
+        assert_eq!(stack("OVER"), ints(&[1, 2, 3, 2]));
+        assert_eq!(stack("ROT"), ints(&[2, 3, 1]));
+        assert_eq!(stack("PICK 2"), ints(&[1, 2, 3, 1]));
+        assert_eq!(stack("ROLL 2"), ints(&[2, 3, 1]));
+        assert_eq!(stack("ROLL 0"), ints(&[1, 2, 3]));
+        assert_eq!(stack("DROP 2"), ints(&[1]));
+        assert_eq!(stack("DUP2"), ints(&[1, 2, 3, 2, 3]));
+        assert_eq!(stack("DEPTH").last(), Some(&Value::UInt(3)));
+    }
+
+    #[test]
+    fn depth_counts_function_frame() {
+        let vm = run("PUSHI 1\nPUSHI 2\nCALL f\nEXIT_IMM 0\nFUNC f 1 2\nDEPTH\nRET");
+        assert_eq!(
+            vm.data_stack(),
+            [Value::Int(1), Value::Int(2), Value::UInt(1)]
+        );
+    }
+
+    #[test]
+    fn stack_manipulation_underflow() {
+        assert_eq!(error("PUSHI 1\nOVER"), "Stack underflow on OVER");
+        assert_eq!(error("PUSHI 1\nPUSHI 2\nROT"), "Stack underflow on ROT");
+        assert_eq!(error("PUSHI 1\nPICK 1"), "Stack underflow on PICK");
+        assert_eq!(error("PUSHI 1\nROLL 1"), "Stack underflow on ROLL");
+        assert_eq!(error("PUSHI 1\nDROP 2"), "Stack underflow on DROP");
+        assert_eq!(error("PUSHI 1\nDUP2"), "Stack underflow on DUP2");
+        assert_eq!(
+            error("PUSHI 1\nCALL f\nEXIT_IMM 0\nFUNC f 0 0\nROT"),
+            "Stack underflow on ROT"
+        );
     }
 }